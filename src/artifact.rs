@@ -1,319 +1,486 @@
 use crate::error::{KnownErrors, KnownErrorsHelper, Result};
-use async_std::{fs::File, path::Path, stream::StreamExt};
+use crate::record::{Artifact, WorkRecordMap};
+use async_std::{
+    path::{Path, PathBuf},
+    stream::StreamExt,
+};
+use async_trait::async_trait;
 use futures::future::try_join_all;
+use std::collections::HashMap;
 
-#[derive(Debug)]
-pub struct ConnectorBuilder {
-    access_key: String,
-    secret_key: String,
-    bucket: String,
-    region_opt: Option<String>,
-    endpoint_opt: Option<String>,
-    path_style: bool,
+pub mod bucket;
+mod file;
+
+/// key of the artifact `name` of the work: `<target_id>/<work_name>/<name>`.
+pub fn artifact_key(target_id: &str, work_name: &str, name: &str) -> String {
+    format!("{}/{}/{}", target_id, work_name, name)
 }
-impl ConnectorBuilder {
-    pub fn new_from_env() -> Result<Self> {
-        use crate::envvar;
-        let access_key = envvar::s3_access_key()?;
-        let secret_key = envvar::s3_secret_key()?;
-        let bucket = envvar::s3_bucket()?;
-        let region_opt = envvar::s3_region_opt();
-        let endpoint_opt = envvar::s3_endpoint_opt();
-        let path_style = envvar::s3_path_style()?;
-        Ok(Self {
-            access_key: access_key,
-            secret_key: secret_key,
-            bucket: bucket,
-            region_opt: region_opt,
-            endpoint_opt: endpoint_opt,
-            path_style: path_style,
-        })
-    }
+
+/// the artifacts under this directory are reserved for the logs of the work.
+pub const LOGS_DIR: &str = "_logs";
+
+/// key of the log file `name` of the work: `<target_id>/<work_name>/_logs/<name>`.
+pub fn log_key(target_id: &str, work_name: &str, name: &str) -> String {
+    artifact_key(target_id, work_name, &format!("{}/{}", LOGS_DIR, name))
+}
+
+fn download_error(key: &str, path: &Path) -> KnownErrors {
+    KnownErrors::Normal(
+        format!("fail to download {} to {}", key, path.to_str().unwrap()),
+        false,
+    )
+}
+
+/// Where artifacts are stored.
+/// S3 is selected by default, and `LW_ARTIFACT_BACKEND=file:///path/to/dir` selects the local directory.
+#[async_trait]
+pub trait ArtifactStore: Send + Sync {
+    async fn upload_file(&self, key: &str, path: &Path) -> Result<()>;
+    async fn download_file(&self, key: &str, path: &Path) -> Result<()>;
+    /// returns the keys which start with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
     #[allow(dead_code)]
-    pub fn access_key(self, val: &str) -> Self {
-        Self {
-            access_key: val.to_string(),
-            secret_key: self.secret_key,
-            bucket: self.bucket,
-            region_opt: self.region_opt,
-            endpoint_opt: self.endpoint_opt,
-            path_style: self.path_style,
-        }
+    async fn delete(&self, key: &str) -> Result<()>;
+    async fn exists(&self, key: &str) -> Result<bool>;
+
+    /// download the artifacts of `depends` to `<outdir>/<work_name>/`.
+    /// An artifact which is not a file is taken as a directory or a path prefix,
+    /// and all artifacts under it are downloaded keeping their relative paths.
+    /// They are resolved against the artifacts recorded in `works`,
+    /// or the listing of the store if the record has no artifacts.
+    /// The downloaded files are verified with the checksums recorded in `works`.
+    async fn download(
+        &self,
+        target_id: &str,
+        depends: &[crate::envvar::Depend],
+        works: &WorkRecordMap,
+        outdir: &Path,
+    ) -> Result<()> {
+        let f_depends = depends.iter().flat_map(|dep| {
+            let work_name = &dep.work_name;
+            let recorded = works
+                .get(&dep.work_name)
+                .map_or(&[][..], |w| &w.artifacts[..]);
+            dep.artifacts.iter().map(move |artifact| {
+                download_artifact(self, target_id, work_name, artifact, recorded, outdir)
+            })
+        });
+        try_join_all(f_depends).await?;
+        Ok(())
     }
-    #[allow(dead_code)]
-    pub fn secret_key(self, val: &str) -> Self {
-        Self {
-            access_key: self.access_key,
-            secret_key: val.to_string(),
-            bucket: self.bucket,
-            region_opt: self.region_opt,
-            endpoint_opt: self.endpoint_opt,
-            path_style: self.path_style,
+    /// expand the glob patterns in the artifacts of `depend`, such as `*.wav` or `stems/**`.
+    /// `*` means all artifacts. They are matched against the artifacts in `work_record`,
+    /// or the listing of the store if the record has no artifacts.
+    async fn resolve(
+        &self,
+        target_id: &str,
+        depend: &crate::envvar::Depend,
+        work_record: &crate::record::WorkRecord,
+    ) -> Result<crate::envvar::Depend> {
+        if !depend.artifacts.iter().any(|a| is_pattern(a)) {
+            return Ok(depend.clone());
         }
+        let available: Vec<String> = if work_record.artifacts.is_empty() {
+            let base = artifact_key(target_id, &depend.work_name, "");
+            self.list(&base)
+                .await?
+                .iter()
+                .map(|k| k[base.len()..].to_string())
+                .filter(|name| !name.starts_with(&format!("{}/", LOGS_DIR)))
+                .collect()
+        } else {
+            work_record
+                .artifacts
+                .iter()
+                .map(|a| a.name.clone())
+                .collect()
+        };
+        let mut artifacts = Vec::new();
+        for a in depend.artifacts.iter() {
+            let matched = match_artifacts(a, &available)?;
+            if matched.is_empty() {
+                return KnownErrors::normal(
+                    &format!("no artifacts of work '{}' match: {}", depend.work_name, a),
+                    false,
+                );
+            }
+            artifacts.extend(
+                matched
+                    .into_iter()
+                    .filter(|m| !artifacts.contains(m))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        Ok(crate::envvar::Depend {
+            artifacts,
+            ..depend.clone()
+        })
     }
+    /// upload the files under `dir` recursively as the artifacts of `work_name`,
+    /// and returns their entries named by the paths relative to `dir`.
     #[allow(dead_code)]
-    pub fn bucket(self, val: &str) -> Self {
-        Self {
-            access_key: self.access_key,
-            secret_key: self.secret_key,
-            bucket: val.to_string(),
-            region_opt: self.region_opt,
-            endpoint_opt: self.endpoint_opt,
-            path_style: self.path_style,
+    async fn upload(&self, target_id: &str, work_name: &str, dir: &Path) -> Result<Vec<Artifact>> {
+        self.upload_with(target_id, work_name, dir, None).await
+    }
+    /// same as `upload`, but only the files which match `publish` are uploaded if it is given.
+    /// each entry must match some files, and its content type overrides the one by the extension.
+    async fn upload_with(
+        &self,
+        target_id: &str,
+        work_name: &str,
+        dir: &Path,
+        publish: Option<&[Publish]>,
+    ) -> Result<Vec<Artifact>> {
+        let mut files = Vec::new();
+        for path in walk(dir).await?.into_iter() {
+            let filename = path
+                .strip_prefix(dir)
+                .ok()
+                .and_then(|p| p.to_str())
+                .ok_or("Path#strip_prefix")
+                .known_error("fail to get filename", false)?
+                .to_string();
+            files.push((filename, path));
+        }
+        files.sort();
+        let mut content_types: HashMap<String, String> = HashMap::new();
+        if let Some(publish) = publish {
+            let names: Vec<String> = files.iter().map(|(name, _)| name.clone()).collect();
+            for p in publish.iter() {
+                let matched = match_artifacts(&p.name, &names)?;
+                if matched.is_empty() {
+                    return KnownErrors::normal(
+                        &format!("no artifacts to publish match: {}", p.name),
+                        true,
+                    );
+                }
+                for name in matched.into_iter() {
+                    let content_type = p.content_type.clone().unwrap_or_default();
+                    let current = content_types.entry(name).or_default();
+                    if current.is_empty() {
+                        *current = content_type;
+                    }
+                }
+            }
+            files.retain(|(name, _)| content_types.contains_key(name));
         }
+        //println!("upload files: {:?}", files);
+        let content_types = &content_types;
+        let fts = files.into_iter().map(|(filename, path)| async move {
+            let filename = filename.as_str();
+            if filename.starts_with(&format!("{}/", LOGS_DIR)) {
+                KnownErrors::normal(&format!("invalid output: {} is reserved", filename), true)
+            } else if path.is_file().await {
+                let key = artifact_key(target_id, work_name, filename);
+                let (size, sha256) = checksum(&path).await?;
+                self.upload_file(&key, &path).await?;
+                let content_type = match content_types.get(filename) {
+                    Some(c) if !c.is_empty() => c.clone(),
+                    _ => content_type(filename).to_string(),
+                };
+                Ok(Artifact {
+                    name: filename.to_string(),
+                    size,
+                    sha256,
+                    content_type,
+                    uploaded: mongodb::bson::DateTime::now(),
+                })
+            } else {
+                KnownErrors::normal(&format!("invalid output: {}", filename), false)
+            }
+        });
+        let r = try_join_all(fts).await?;
+        Ok(r)
     }
-    #[allow(dead_code)]
-    pub fn region(self, val: Option<&str>) -> Self {
-        Self {
-            access_key: self.access_key,
-            secret_key: self.secret_key,
-            bucket: self.bucket,
-            region_opt: val.map(|s| s.to_string()),
-            endpoint_opt: self.endpoint_opt,
-            path_style: self.path_style,
+}
+
+/// an artifact which `result.json` publishes, by the name or the glob pattern.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(from = "PublishRepr")]
+pub struct Publish {
+    pub name: String,
+    pub content_type: Option<String>,
+}
+
+/// either `"a.wav"` or `{ "name": "a.wav", "content_type": "audio/wav" }`.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum PublishRepr {
+    Name(String),
+    Entry(PublishEntry),
+}
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PublishEntry {
+    name: String,
+    content_type: Option<String>,
+}
+impl From<PublishRepr> for Publish {
+    fn from(r: PublishRepr) -> Self {
+        match r {
+            PublishRepr::Name(name) => Publish {
+                name,
+                content_type: None,
+            },
+            PublishRepr::Entry(e) => Publish {
+                name: e.name,
+                content_type: e.content_type,
+            },
         }
     }
-    #[allow(dead_code)]
-    pub fn endpoint(self, val: Option<&str>) -> Self {
-        Self {
-            access_key: self.access_key,
-            secret_key: self.secret_key,
-            bucket: self.bucket,
-            region_opt: self.region_opt,
-            endpoint_opt: val.map(|s| s.to_string()),
-            path_style: self.path_style,
+}
+
+fn find<'a>(recorded: &'a [Artifact], name: &str) -> Option<&'a Artifact> {
+    recorded.iter().find(|a| a.name == name)
+}
+
+/// returns the size and the hex encoded SHA-256 of the file.
+pub async fn checksum(path: &Path) -> Result<(u64, String)> {
+    use async_std::io::ReadExt;
+    use sha2::Digest;
+    let mut file = async_std::fs::File::open(path)
+        .await
+        .known_error(&format!("fail to open file: {}", path.display()), false)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .known_error(&format!("fail to read file: {}", path.display()), false)?;
+        if n == 0 {
+            break;
         }
+        hasher.update(&buf[..n]);
+        size += n as u64;
     }
-    #[allow(dead_code)]
-    pub fn path_style(self, val: bool) -> Self {
-        Self {
-            access_key: self.access_key,
-            secret_key: self.secret_key,
-            bucket: self.bucket,
-            region_opt: self.region_opt,
-            endpoint_opt: self.endpoint_opt,
-            path_style: val,
+    Ok((size, hex::encode(hasher.finalize())))
+}
+
+/// verify the downloaded file with the recorded entry.
+/// Nothing is checked if the entry is not found or has no checksum, such as the records written by old versions.
+async fn verify(key: &str, path: &Path, expected: Option<&Artifact>) -> Result<()> {
+    let expected = match expected {
+        Some(a) if !a.sha256.is_empty() => a,
+        _ => return Ok(()),
+    };
+    let (size, sha256) = checksum(path).await?;
+    if size != expected.size || sha256 != expected.sha256 {
+        return KnownErrors::normal(
+            &format!(
+                "checksum mismatch: {}: expected {} bytes sha256={} but {} bytes sha256={}",
+                key, expected.size, expected.sha256, size, sha256
+            ),
+            false,
+        );
+    }
+    Ok(())
+}
+
+fn content_type(name: &str) -> &'static str {
+    let ext = name.rsplit('.').next().unwrap_or("").to_ascii_lowercase();
+    match ext.as_str() {
+        "txt" => "text/plain",
+        "json" => "application/json",
+        "csv" => "text/csv",
+        "html" => "text/html",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "mp4" => "video/mp4",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "zip" => "application/zip",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(crate) fn is_pattern(artifact: &str) -> bool {
+    artifact.contains(['*', '?', '['])
+}
+
+/// returns the names in `available` which match the glob `pattern`.
+/// `*` does not match `/` except for the `*` shorthand which matches everything.
+pub(crate) fn match_artifacts(pattern: &str, available: &[String]) -> Result<Vec<String>> {
+    if pattern == "*" {
+        return Ok(available.to_vec());
+    }
+    let p = glob::Pattern::new(pattern)
+        .known_error(&format!("invalid artifact pattern: {}", pattern), true)?;
+    let options = glob::MatchOptions {
+        case_sensitive: true,
+        require_literal_separator: true,
+        require_literal_leading_dot: false,
+    };
+    Ok(available
+        .iter()
+        .filter(|a| p.matches_with(a, options))
+        .cloned()
+        .collect())
+}
+
+/// download the artifact, or all artifacts under it, of `work_name` to `<outdir>/<work_name>/`.
+async fn download_artifact(
+    store: &(impl ArtifactStore + ?Sized),
+    target_id: &str,
+    work_name: &str,
+    artifact: &str,
+    recorded: &[Artifact],
+    outdir: &Path,
+) -> Result<()> {
+    let key = artifact_key(target_id, work_name, artifact);
+    let names: Vec<String> = if !recorded.is_empty() {
+        if find(recorded, artifact).is_some() {
+            vec![artifact.to_string()]
+        } else {
+            recorded
+                .iter()
+                .filter(|a| a.name.starts_with(artifact))
+                .map(|a| a.name.clone())
+                .collect()
         }
+    } else if store.exists(&key).await? {
+        vec![artifact.to_string()]
+    } else {
+        // the records written by old versions have no artifacts
+        let base = artifact_key(target_id, work_name, "");
+        store
+            .list(&key)
+            .await?
+            .iter()
+            .map(|k| k[base.len()..].to_string())
+            .collect()
+    };
+    if names.is_empty() {
+        let outpath = outdir.join(work_name).join(artifact);
+        return Err(Box::new(download_error(&key, &outpath)));
+    }
+    for name in names.iter() {
+        let key = artifact_key(target_id, work_name, name);
+        let outpath = outdir.join(work_name).join(name);
+        download_to(store, &key, &outpath).await?;
+        verify(&key, &outpath, find(recorded, name)).await?;
+    }
+    Ok(())
+}
+
+/// download `key` to `path`, creating the parent directories.
+async fn download_to(store: &(impl ArtifactStore + ?Sized), key: &str, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        async_std::fs::create_dir_all(parent)
+            .await
+            .known_error(&format!("fail to mkdir: {}", parent.display()), false)?;
     }
-    pub fn build(self) -> Result<Connector> {
-        Connector::new(
-            self.access_key,
-            self.secret_key,
-            self.bucket,
-            self.region_opt,
-            self.endpoint_opt,
-            self.path_style,
-        )
+    store.download_file(key, path).await
+}
+
+/// returns the paths of the entries under `dir` recursively, except directories.
+pub async fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![dir.to_path_buf()];
+    let mut paths = Vec::new();
+    while let Some(d) = dirs.pop() {
+        let mut entries = async_std::fs::read_dir(&d)
+            .await
+            .known_error(&format!("fail to read dir: {}", d.display()), false)?;
+        while let Some(entry) = entries.next().await {
+            let entry = entry.known_error("fail to read dir entry", false)?;
+            let file_type = entry
+                .file_type()
+                .await
+                .known_error("fail to get file type", false)?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                paths.push(entry.path());
+            }
+        }
     }
+    Ok(paths)
 }
 
 #[derive(Debug, Clone)]
-pub struct Connector {
-    pub endpoint: Option<String>,
-    pub region: s3::region::Region,
-    pub credentials: s3::creds::Credentials,
-    pub bucketname: String,
-    pub path_style: bool,
+#[allow(clippy::large_enum_variant)]
+pub enum Connector {
+    S3(bucket::Connector),
+    File(file::Connector),
 }
 impl Connector {
     pub fn new_from_env() -> Result<Self> {
-        let b = ConnectorBuilder::new_from_env()?;
-        let c = b.build()?;
-        Ok(c)
-    }
-    pub fn new(
-        access_key: String,
-        secret_key: String,
-        bucketname: String,
-        region_opt: Option<String>,
-        endpoint_opt: Option<String>,
-        path_style: bool,
-    ) -> Result<Self> {
-        let s = Self {
-            endpoint: endpoint_opt.clone(),
-            region: match (region_opt, endpoint_opt) {
-                (_, Some(ep)) => s3::Region::Custom {
-                    region: "use-east-1".into(),
-                    endpoint: ep,
-                },
-                (Some(r), _) => {
-                    use std::str::FromStr;
-                    s3::Region::from_str(&r)?
+        use crate::envvar;
+        let c = match envvar::artifact_backend_opt() {
+            None => Connector::S3(bucket::Connector::new_from_env()?),
+            Some(s) if s == "s3" => Connector::S3(bucket::Connector::new_from_env()?),
+            Some(s) => match s.strip_prefix("file://") {
+                Some(dir) => Connector::File(file::Connector::new(dir)),
+                None => {
+                    return KnownErrors::normal(&format!("unknown artifact backend: {}", s), true)
                 }
-                _ => Err("").known_error_required("s3_region or s3_endpoint")?,
             },
-            credentials: s3::creds::Credentials::new(
-                Some(&access_key),
-                Some(&secret_key),
-                None,
-                None,
-                None,
-            )?,
-            bucketname: bucketname,
-            path_style: path_style,
         };
-        Ok(s)
+        Ok(c)
     }
-
-    #[allow(dead_code)]
-    pub fn bucket(&self) -> Result<s3::Bucket> {
-        //println!("connect to {:?}", self.endpoint);
-        let b = match self.path_style {
-            false => s3::Bucket::new(
-                &self.bucketname,
-                self.region.clone(),
-                self.credentials.clone(),
-            )
-            .known_error("fail to connect bucket", false)?,
-            true => s3::Bucket::new_with_path_style(
-                &self.bucketname,
-                self.region.clone(),
-                self.credentials.clone(),
-            )
-            .known_error("fail to connect bucket", false)?,
-        };
-        Ok(b)
+    pub fn store(&self) -> &dyn ArtifactStore {
+        match self {
+            Connector::S3(c) => c,
+            Connector::File(c) => c,
+        }
     }
-    #[allow(dead_code)]
-    pub async fn create_bucket(
-        &self,
-        conf: &s3::bucket_ops::BucketConfiguration,
-    ) -> Result<s3::bucket_ops::CreateBucketResponse> {
-        let r = match self.path_style {
-            false => {
-                s3::Bucket::create(
-                    &self.bucketname,
-                    self.region.clone(),
-                    self.credentials.clone(),
-                    conf.clone(),
-                )
-                .await?
-            }
-            true => {
-                s3::Bucket::create_with_path_style(
-                    &self.bucketname,
-                    self.region.clone(),
-                    self.credentials.clone(),
-                    conf.clone(),
-                )
-                .await?
-            }
-        };
-        Ok(r)
+    /// the number of the retried operations. the local directory is not retried.
+    pub fn retried(&self) -> u32 {
+        match self {
+            Connector::S3(c) => c.retry.retried(),
+            Connector::File(_) => 0,
+        }
     }
-    #[allow(dead_code)]
-    pub async fn download<P: AsRef<Path>>(
-        &self,
-        target_id: &str,
-        depends: &Vec<crate::envvar::Depend>,
-        outdir: P,
-    ) -> Result<()> {
-        let outdir = outdir.as_ref();
-        let f_depends = depends.iter().flat_map(|dep| {
-            let work_name = dep.work_name.clone();
-            dep.artifacts.iter().map(move |artifact| {
-                let conn = self.clone();
-                let outdir = outdir.clone();
-                let work_name = work_name.clone();
-                async move {
-                    let outpath = outdir.join(work_name.clone()).join(artifact);
-                    //let mut io = async_std::fs::File::create(path)
-                    //  .await
-                    let bucket = conn
-                        .bucket()
-                        .known_error_normal(&format!("cannot connect to s3"), false)?;
-                    let mut outfile = std::fs::File::create(outpath.clone()).known_error_normal(
-                        &format!("fail to create file: {}", outpath.display()),
-                        false,
-                    )?;
-                    let s3_path = format!("{}/{}/{}", target_id, work_name, artifact);
-                    let code = bucket.get_object_stream(&s3_path, &mut outfile).await?;
-                    if code != 200 {
-                        return KnownErrors::normal(
-                            &format!(
-                                "fail to download {} to {}",
-                                s3_path,
-                                outpath.to_str().unwrap()
-                            ),
-                            false,
-                        );
+}
+
+/// remove all artifacts of the test connector. S3 bucket is recreated.
+#[cfg(test)]
+pub async fn reset(connector: &Connector) -> Result<()> {
+    match connector {
+        Connector::S3(conn) => {
+            let bucket = conn.bucket()?;
+            // at first try to delete bucket because to call list against empty bucket raise panic...
+            let r = bucket.delete().await.unwrap_or(0u16);
+            match r {
+                409 => {
+                    // conflicts. it occures when bucket has any objects.
+                    for r in bucket.list("/".to_string(), None).await?.iter() {
+                        //println!("list item: {:?}", r);
+                        for c in r.contents.iter() {
+                            //println!("delete object: {}", c.key);
+                            let (_, _r) = bucket.delete_object(&c.key).await?;
+                            //println!("  code={}", r); //204
+                        }
                     }
-                    let _ = outfile.sync_all();
-                    /*
-                    println!(
-                        "download: {} to {}: code={})",
-                        s3_path,
-                        outpath.to_str().unwrap(),
-                        code
-                    );
-                     */
-                    Result::Ok(code)
-                } // end of move
-            })
-        });
-        try_join_all(f_depends).await?;
-        Ok(())
-    }
-    #[allow(dead_code)]
-    pub async fn upload<P: AsRef<Path>>(
-        &self,
-        target_id: &str,
-        work_name: &str,
-        dir: P,
-    ) -> Result<Vec<String>> {
-        let dir = dir.as_ref();
-        let dir_entries = dir
-            .read_dir() //Future<Result<ReadDir impl Stream<Result<DirEntry>> >>
-            .await?
-            // .collect::<Result<Vec<_>, _>>()? //Result<Vec<DirEntry>>? //nightly
-            .fold(Ok(Vec::new()), |mut r_acc, r_entry| {
-                if r_acc.is_err() {
-                    r_acc
-                } else if let Err(e) = r_entry {
-                    Err(e)
-                } else if let Ok(ref mut v) = r_acc {
-                    v.push(r_entry.unwrap().clone());
-                    r_acc
-                } else {
-                    r_acc
+                    let r = bucket.delete().await.unwrap_or(0u16);
+                    assert_eq!(r, 204);
                 }
-            })
-            .await?;
-        //println!("upload files: {:?}", dir_entries);
-        let fts = dir_entries.into_iter().map(|e| {
-            let conn = self.clone();
-            async move {
-                let path = e.path();
-                let filename = path
-                    .file_name()
-                    .and_then(|s| s.to_str())
-                    .ok_or("Path#file_name")
-                    .known_error("fail to get filename", false)?;
-                if e.file_type().await?.is_file() {
-                    let s3_path = format!("{}/{}/{}", target_id, work_name, filename);
-                    /*
-                    println!(
-                        "upload \"{}\" to \"{}\" ...",
-                        path.to_str().unwrap(),
-                        s3_path
-                    );
-                    */
-                    let bucket = conn.bucket()?;
-                    let mut io = File::open(path.clone())
-                        .await
-                        .known_error(&format!("fail to open file: {}", path.display()), false)?;
-                    let _r = bucket.put_object_stream(&mut io, s3_path).await?;
-                    //println!("write status={}", _r);
-                    let _r = io.sync_all().await?;
-                    Ok(filename.to_string())
-                } else {
-                    KnownErrors::normal(&format!("invalid output: {}", filename), false)
+                204 => (),
+                404 => (),
+                _ => {
+                    assert!(false, "fail to delete bucket: {}", r);
                 }
             }
-        });
-        let r = try_join_all(fts).await?;
-        Ok(r)
+            let conf = s3::bucket_ops::BucketConfiguration::public();
+            let r = conn.create_bucket(&conf).await?;
+            /*
+                    if !r.success() {
+                        println!("{}", r.response_text);
+                    }
+            */
+            assert!(r.success(), "fail to create bucket: {}", conn.bucketname);
+        }
+        Connector::File(_) => {
+            let store = connector.store();
+            for key in store.list("").await?.iter() {
+                store.delete(key).await?;
+            }
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -327,10 +494,9 @@ mod tests {
         pub conn: Connector,
         pub indir: String,
         pub outdir: String,
-        pub bucketname: String,
-        pub bucket: s3::Bucket,
     }
     async fn setup(workname: &str) -> Result<Setup> {
+        crate::envvar::set_test_defaults();
         let indir = async_std::path::Path::new("/tmp/artifact-in");
         let outdir = async_std::path::Path::new("/tmp/artifact-out");
         if indir.exists().await {
@@ -353,51 +519,26 @@ mod tests {
             async_std::fs::create_dir(&outdir2).await?;
         }
 
-        let bucketname = "test-artifact";
-        let conn = ConnectorBuilder::new_from_env()?
-            .bucket(bucketname)
-            .build()?;
-        println!("bucket.url = {}", conn.bucket()?.url());
-        let bucket = conn.bucket()?;
-        // at first try to delete bucket because to call list against empty bucket raise panic...
-        let r = bucket.delete().await.unwrap_or(0u16);
-        match r {
-            409 => {
-                // conflicts. it occures when bucket has any objects.
-                for r in bucket.list("/".to_string(), None).await?.iter() {
-                    //println!("list item: {:?}", r);
-                    for c in r.contents.iter() {
-                        //println!("delete object: {}", c.key);
-                        let (_, _r) = bucket.delete_object(&c.key).await?;
-                        //println!("  code={}", r); //204
-                    }
-                }
-                let r = bucket.delete().await.unwrap_or(0u16);
-                assert_eq!(r, 204);
+        let conn = match Connector::new_from_env()? {
+            Connector::S3(_) => {
+                let bucketname = "test-artifact";
+                let conn = bucket::ConnectorBuilder::new_from_env()?
+                    .bucket(bucketname)
+                    .build()?;
+                println!("bucket.url = {}", conn.bucket()?.url());
+                Connector::S3(conn)
             }
-            204 => (),
-            404 => (),
-            _ => {
-                assert!(false, "fail to delete bucket: {}", r);
-            }
-        }
-        let conf = s3::bucket_ops::BucketConfiguration::public();
-        let r = conn.create_bucket(&conf).await?;
-        /*
-                if !r.success() {
-                    println!("{}", r.response_text);
-                }
-        */
-        assert!(r.success(), "fail to create bucket: {}", bucketname);
-        bucket
-            .put_object("/test/up.txt", "Hello World".as_bytes())
-            .await?;
+            conn => conn,
+        };
+        reset(&conn).await?;
+        let up = indir.join("up.txt");
+        async_std::fs::write(&up, "Hello World".as_bytes()).await?;
+        conn.store().upload_file("/test/up.txt", &up).await?;
+        async_std::fs::remove_file(&up).await?;
         Ok(Setup {
             conn: conn,
             indir: indir.to_str().unwrap().to_string(),
             outdir: outdir.to_str().unwrap().to_string(),
-            bucketname: bucketname.to_string(),
-            bucket: bucket,
         })
     }
 
@@ -415,6 +556,7 @@ mod tests {
         filename: String,
         content: Vec<u8>,
         s3_path: String,
+        artifacts: Vec<Artifact>,
     }
     async fn upload(work_name: &str) -> Result<Upload> {
         let setup = setup(work_name).await?;
@@ -434,7 +576,7 @@ mod tests {
         let r = file.sync_all().await;
         assert_matches!(r, Ok(_));
 
-        let r = setup.conn.upload(target_id, work_name, indir).await;
+        let r = setup.conn.store().upload(target_id, work_name, indir).await;
         assert_matches!(r, Ok(_));
 
         Ok(Upload {
@@ -443,8 +585,33 @@ mod tests {
             filename: filename.clone(),
             content: content.to_vec(),
             s3_path: format!("{}/{}/{}", target_id, work_name, filename),
+            artifacts: r.unwrap(),
         })
     }
+    fn works(work_name: &str, artifacts: &[Artifact]) -> WorkRecordMap {
+        let mut works = WorkRecordMap::new();
+        works.insert(
+            work_name.to_string(),
+            crate::record::WorkRecord {
+                name: work_name.to_string(),
+                version: "3.9".to_string(),
+                status: crate::record::WorkStatus::Succeeded,
+                error: None,
+                error_kind: None,
+                updated: mongodb::bson::DateTime::now(),
+                artifacts: artifacts.to_vec(),
+                metadata: crate::record::Document::new(),
+                lease: None,
+                started: None,
+                exit_code: None,
+                host: None,
+                retries: crate::record::Retries::new(),
+                attempts: 0,
+                message: None,
+            },
+        );
+        works
+    }
     #[async_std::test]
     #[serial]
     async fn test_artifact_upload() -> Result<()> {
@@ -455,10 +622,22 @@ mod tests {
             u.unwrap()
         };
 
-        let r = u.setup.bucket.get_object(&u.s3_path).await;
+        assert_eq!(u.artifacts.len(), 1);
+        let a = &u.artifacts[0];
+        assert_eq!(a.name, u.filename);
+        assert_eq!(a.size, 8);
+        assert_eq!(
+            a.sha256,
+            "07012f719db7dd9b03e42e9d536756ac3104299c62c0224182ad9b43e0011d0c"
+        );
+        assert_eq!(a.content_type, "text/plain");
+
+        let store = u.setup.conn.store();
+        assert_matches!(store.exists(&u.s3_path).await, Ok(true));
+        let outpath = async_std::path::Path::new(&u.setup.outdir).join(&u.filename);
+        let r = store.download_file(&u.s3_path, &outpath).await;
         assert_matches!(r, Ok(_));
-        let (data, code) = r.unwrap();
-        assert_eq!(code, 200);
+        let data = async_std::fs::read(&outpath).await?;
         assert_eq!(data, u.content);
         Ok(())
     }
@@ -482,7 +661,13 @@ mod tests {
         let r = u
             .setup
             .conn
-            .download(&u.target_id, &vec![depend], outdir)
+            .store()
+            .download(
+                &u.target_id,
+                &[depend],
+                &works(workname, &u.artifacts),
+                outdir,
+            )
             .await;
         assert_matches!(r, Ok(_));
 
@@ -495,4 +680,145 @@ mod tests {
         assert_eq!(buf, u.content);
         Ok(())
     }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_artifact_upload_download_nested() -> Result<()> {
+        let workname = "test_artifact_upload_download_nested";
+        let setup = setup(workname).await?;
+        let target_id = "39";
+        let indir = async_std::path::Path::new(&setup.indir);
+        let files = [
+            "top.txt",
+            "stems/bass/1.wav",
+            "stems/bass/2.wav",
+            "stems/drum.wav",
+        ];
+        for f in files.iter() {
+            let path = indir.join(f);
+            async_std::fs::create_dir_all(path.parent().unwrap()).await?;
+            async_std::fs::write(&path, f.as_bytes()).await?;
+        }
+
+        let store = setup.conn.store();
+        let r = store.upload(target_id, workname, indir).await;
+        assert_matches!(r, Ok(_));
+        let artifacts = r.unwrap();
+        let mut uploaded = artifacts.iter().map(|a| a.name.clone()).collect::<Vec<_>>();
+        uploaded.sort();
+        let mut expected = files.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(uploaded, expected);
+
+        let depend = crate::envvar::Depend {
+            work_name: workname.to_string(),
+            work_version: "3.9".to_string(),
+            artifacts: vec!["top.txt".to_string(), "stems/bass".to_string()],
+        };
+        let outdir = async_std::path::Path::new(&setup.outdir);
+        let r = store
+            .download(target_id, &[depend], &WorkRecordMap::new(), outdir)
+            .await;
+        assert_matches!(r, Ok(_));
+        for f in ["top.txt", "stems/bass/1.wav", "stems/bass/2.wav"].iter() {
+            let data = async_std::fs::read(outdir.join(workname).join(f)).await?;
+            assert_eq!(data, f.as_bytes());
+        }
+        assert!(!outdir.join(workname).join("stems/drum.wav").exists().await);
+
+        let depend = crate::envvar::Depend {
+            work_name: workname.to_string(),
+            work_version: "3.9".to_string(),
+            artifacts: vec!["nothing".to_string()],
+        };
+        let r = store
+            .download(target_id, &[depend], &WorkRecordMap::new(), outdir)
+            .await;
+        assert_matches!(r, Err(_));
+
+        // a stale object which is not recorded is not downloaded
+        let stale = indir.join("stale.wav");
+        async_std::fs::write(&stale, "stale".as_bytes()).await?;
+        store
+            .upload_file(
+                &artifact_key(target_id, workname, "stems/bass/3.wav"),
+                &stale,
+            )
+            .await?;
+        async_std::fs::remove_dir_all(outdir.join(workname)).await?;
+        let depend = crate::envvar::Depend {
+            work_name: workname.to_string(),
+            work_version: "3.9".to_string(),
+            artifacts: vec!["stems/bass".to_string()],
+        };
+        let r = store
+            .download(target_id, &[depend], &works(workname, &artifacts), outdir)
+            .await;
+        assert_matches!(r, Ok(_));
+        for f in ["stems/bass/1.wav", "stems/bass/2.wav"].iter() {
+            let data = async_std::fs::read(outdir.join(workname).join(f)).await?;
+            assert_eq!(data, f.as_bytes());
+        }
+        assert!(
+            !outdir
+                .join(workname)
+                .join("stems/bass/3.wav")
+                .exists()
+                .await
+        );
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_artifact_checksum_mismatch() -> Result<()> {
+        let workname = "test_artifact_checksum_mismatch";
+        let u = upload(workname).await?;
+
+        // the stored object is truncated after it is recorded
+        let store = u.setup.conn.store();
+        let broken = async_std::path::Path::new(&u.setup.indir).join("broken");
+        async_std::fs::write(&broken, &u.content[..4]).await?;
+        store.upload_file(&u.s3_path, &broken).await?;
+
+        let depend = crate::envvar::Depend {
+            work_name: workname.to_string(),
+            work_version: "3.9".to_string(),
+            artifacts: vec![u.filename.clone()],
+        };
+        let outdir = async_std::path::Path::new(&u.setup.outdir);
+        let r = store
+            .download(
+                &u.target_id,
+                &[depend],
+                &works(workname, &u.artifacts),
+                outdir,
+            )
+            .await;
+        let e = r.err().unwrap();
+        assert_matches!(
+            e.downcast_ref::<KnownErrors>(),
+            Some(KnownErrors::Normal(msg, false)) if msg.starts_with("checksum mismatch: ")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_match_artifacts() {
+        let available = ["a.wav", "b.txt", "stems/bass.wav", "stems/drum/1.wav"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>();
+        let m = |p: &str| match_artifacts(p, &available).unwrap();
+        assert_eq!(m("*"), available);
+        assert_eq!(m("*.wav"), vec!["a.wav"]);
+        assert_eq!(m("stems/**"), vec!["stems/bass.wav", "stems/drum/1.wav"]);
+        assert_eq!(
+            m("**/*.wav"),
+            vec!["a.wav", "stems/bass.wav", "stems/drum/1.wav"]
+        );
+        assert_eq!(m("?.txt"), vec!["b.txt"]);
+        assert!(m("*.mp3").is_empty());
+        assert_matches!(match_artifacts("[", &available), Err(_));
+    }
 }
@@ -0,0 +1,287 @@
+use super::{download_error, ArtifactStore};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::retry::{Policy, Stage};
+use async_std::{fs::File, path::Path};
+use async_trait::async_trait;
+
+#[derive(Debug)]
+pub struct ConnectorBuilder {
+    access_key: String,
+    secret_key: String,
+    bucket: String,
+    region_opt: Option<String>,
+    endpoint_opt: Option<String>,
+    path_style: bool,
+}
+impl ConnectorBuilder {
+    pub fn new_from_env() -> Result<Self> {
+        use crate::envvar;
+        let access_key = envvar::s3_access_key()?;
+        let secret_key = envvar::s3_secret_key()?;
+        let bucket = envvar::s3_bucket()?;
+        let region_opt = envvar::s3_region_opt();
+        let endpoint_opt = envvar::s3_endpoint_opt();
+        let path_style = envvar::s3_path_style()?;
+        Ok(Self {
+            access_key: access_key,
+            secret_key: secret_key,
+            bucket: bucket,
+            region_opt: region_opt,
+            endpoint_opt: endpoint_opt,
+            path_style: path_style,
+        })
+    }
+    #[allow(dead_code)]
+    pub fn access_key(self, val: &str) -> Self {
+        Self {
+            access_key: val.to_string(),
+            secret_key: self.secret_key,
+            bucket: self.bucket,
+            region_opt: self.region_opt,
+            endpoint_opt: self.endpoint_opt,
+            path_style: self.path_style,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn secret_key(self, val: &str) -> Self {
+        Self {
+            access_key: self.access_key,
+            secret_key: val.to_string(),
+            bucket: self.bucket,
+            region_opt: self.region_opt,
+            endpoint_opt: self.endpoint_opt,
+            path_style: self.path_style,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn bucket(self, val: &str) -> Self {
+        Self {
+            access_key: self.access_key,
+            secret_key: self.secret_key,
+            bucket: val.to_string(),
+            region_opt: self.region_opt,
+            endpoint_opt: self.endpoint_opt,
+            path_style: self.path_style,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn region(self, val: Option<&str>) -> Self {
+        Self {
+            access_key: self.access_key,
+            secret_key: self.secret_key,
+            bucket: self.bucket,
+            region_opt: val.map(|s| s.to_string()),
+            endpoint_opt: self.endpoint_opt,
+            path_style: self.path_style,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn endpoint(self, val: Option<&str>) -> Self {
+        Self {
+            access_key: self.access_key,
+            secret_key: self.secret_key,
+            bucket: self.bucket,
+            region_opt: self.region_opt,
+            endpoint_opt: val.map(|s| s.to_string()),
+            path_style: self.path_style,
+        }
+    }
+    #[allow(dead_code)]
+    pub fn path_style(self, val: bool) -> Self {
+        Self {
+            access_key: self.access_key,
+            secret_key: self.secret_key,
+            bucket: self.bucket,
+            region_opt: self.region_opt,
+            endpoint_opt: self.endpoint_opt,
+            path_style: val,
+        }
+    }
+    pub fn build(self) -> Result<Connector> {
+        Connector::new(
+            self.access_key,
+            self.secret_key,
+            self.bucket,
+            self.region_opt,
+            self.endpoint_opt,
+            self.path_style,
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Connector {
+    pub region: s3::region::Region,
+    pub credentials: s3::creds::Credentials,
+    pub bucketname: String,
+    pub path_style: bool,
+    pub retry: Policy,
+}
+impl Connector {
+    pub fn new_from_env() -> Result<Self> {
+        let b = ConnectorBuilder::new_from_env()?;
+        let mut c = b.build()?;
+        c.retry = Policy::new_from_env(Stage::Artifact)?;
+        Ok(c)
+    }
+    pub fn new(
+        access_key: String,
+        secret_key: String,
+        bucketname: String,
+        region_opt: Option<String>,
+        endpoint_opt: Option<String>,
+        path_style: bool,
+    ) -> Result<Self> {
+        let s = Self {
+            region: match (region_opt, endpoint_opt) {
+                (_, Some(ep)) => s3::Region::Custom {
+                    region: "use-east-1".into(),
+                    endpoint: ep,
+                },
+                (Some(r), _) => {
+                    use std::str::FromStr;
+                    s3::Region::from_str(&r)?
+                }
+                _ => Err("").known_error_required("s3_region or s3_endpoint")?,
+            },
+            credentials: s3::creds::Credentials::new(
+                Some(&access_key),
+                Some(&secret_key),
+                None,
+                None,
+                None,
+            )?,
+            bucketname: bucketname,
+            path_style: path_style,
+            retry: Policy::new_default(Stage::Artifact),
+        };
+        Ok(s)
+    }
+
+    #[allow(dead_code)]
+    pub fn bucket(&self) -> Result<s3::Bucket> {
+        //println!("connect to {:?}", self.region);
+        let b = match self.path_style {
+            false => s3::Bucket::new(
+                &self.bucketname,
+                self.region.clone(),
+                self.credentials.clone(),
+            )
+            .known_error("fail to connect bucket", false)?,
+            true => s3::Bucket::new_with_path_style(
+                &self.bucketname,
+                self.region.clone(),
+                self.credentials.clone(),
+            )
+            .known_error("fail to connect bucket", false)?,
+        };
+        Ok(b)
+    }
+    #[allow(dead_code)]
+    pub async fn create_bucket(
+        &self,
+        conf: &s3::bucket_ops::BucketConfiguration,
+    ) -> Result<s3::bucket_ops::CreateBucketResponse> {
+        let r = match self.path_style {
+            false => {
+                s3::Bucket::create(
+                    &self.bucketname,
+                    self.region.clone(),
+                    self.credentials.clone(),
+                    conf.clone(),
+                )
+                .await?
+            }
+            true => {
+                s3::Bucket::create_with_path_style(
+                    &self.bucketname,
+                    self.region.clone(),
+                    self.credentials.clone(),
+                    conf.clone(),
+                )
+                .await?
+            }
+        };
+        Ok(r)
+    }
+}
+
+impl Connector {
+    async fn upload_file_once(&self, key: &str, path: &Path) -> Result<()> {
+        let bucket = self.bucket()?;
+        let mut io = File::open(path)
+            .await
+            .known_error(&format!("fail to open file: {}", path.display()), false)?;
+        let _r = bucket.put_object_stream(&mut io, key).await?;
+        //println!("write status={}", _r);
+        let _r = io.sync_all().await?;
+        Ok(())
+    }
+    async fn download_file_once(&self, key: &str, path: &Path) -> Result<()> {
+        let bucket = self
+            .bucket()
+            .known_error_normal(&format!("cannot connect to s3"), false)?;
+        let mut outfile = std::fs::File::create(path)
+            .known_error_normal(&format!("fail to create file: {}", path.display()), false)?;
+        let code = bucket.get_object_stream(key, &mut outfile).await?;
+        if code != 200 {
+            return Err(Box::new(download_error(key, path)));
+        }
+        let _ = outfile.sync_all();
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for Connector {
+    /// retried by `LW_RETRY_ARTIFACT_xxx`.
+    async fn upload_file(&self, key: &str, path: &Path) -> Result<()> {
+        self.retry
+            .run(&format!("upload {}", key), || {
+                self.upload_file_once(key, path)
+            })
+            .await
+    }
+    /// retried by `LW_RETRY_ARTIFACT_xxx`.
+    async fn download_file(&self, key: &str, path: &Path) -> Result<()> {
+        self.retry
+            .run(&format!("download {}", key), || {
+                self.download_file_once(key, path)
+            })
+            .await
+    }
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let bucket = self.bucket()?;
+        let keys = bucket
+            .list(prefix.to_string(), None)
+            .await
+            .known_error(&format!("fail to list {}", prefix), false)?
+            .into_iter()
+            .flat_map(|r| r.contents.into_iter().map(|c| c.key))
+            .collect();
+        Ok(keys)
+    }
+    async fn delete(&self, key: &str) -> Result<()> {
+        let bucket = self.bucket()?;
+        let (_, code) = bucket
+            .delete_object(key)
+            .await
+            .known_error(&format!("fail to delete {}", key), false)?;
+        match code {
+            200 | 204 => Ok(()),
+            _ => KnownErrors::normal(&format!("fail to delete {}: code={}", key, code), false),
+        }
+    }
+    async fn exists(&self, key: &str) -> Result<bool> {
+        let bucket = self.bucket()?;
+        let (_, code) = bucket
+            .head_object(key)
+            .await
+            .known_error(&format!("fail to head {}", key), false)?;
+        match code {
+            200 => Ok(true),
+            404 => Ok(false),
+            _ => KnownErrors::normal(&format!("fail to head {}: code={}", key, code), false),
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use super::{download_error, walk, ArtifactStore};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use async_std::path::{Path, PathBuf};
+use async_trait::async_trait;
+
+/// Each artifact is stored in `<dir>/<key>`, that is `<dir>/<target_id>/<work_name>/<name>`.
+#[derive(Debug, Clone)]
+pub struct Connector {
+    dir: PathBuf,
+}
+impl Connector {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: PathBuf::from(dir),
+        }
+    }
+    /// the path of `key` under the directory. `..` and absolute paths are rejected so as not to escape it.
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key.trim_start_matches('/'));
+        let escapes = relative.components().any(|c| {
+            matches!(
+                c,
+                std::path::Component::ParentDir
+                    | std::path::Component::RootDir
+                    | std::path::Component::Prefix(_)
+            )
+        });
+        if escapes {
+            return KnownErrors::normal(&format!("invalid artifact key: {}", key), true);
+        }
+        Ok(self.dir.join(relative))
+    }
+}
+
+#[async_trait]
+impl ArtifactStore for Connector {
+    async fn upload_file(&self, key: &str, path: &Path) -> Result<()> {
+        let dst = self.path(key)?;
+        if let Some(parent) = dst.parent() {
+            async_std::fs::create_dir_all(parent)
+                .await
+                .known_error(&format!("fail to mkdir: {}", parent.display()), false)?;
+        }
+        async_std::fs::copy(path, &dst).await.known_error(
+            &format!("fail to copy {} to {}", path.display(), dst.display()),
+            false,
+        )?;
+        Ok(())
+    }
+    async fn download_file(&self, key: &str, path: &Path) -> Result<()> {
+        let src = self.path(key)?;
+        if !src.is_file().await {
+            return Err(Box::new(download_error(key, path)));
+        }
+        async_std::fs::copy(&src, path).await.known_error(
+            &format!("fail to copy {} to {}", src.display(), path.display()),
+            false,
+        )?;
+        Ok(())
+    }
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // walk the deepest directory which contains all keys starting with prefix
+        let base = match prefix.rfind('/') {
+            Some(i) => &prefix[..i],
+            None => "",
+        };
+        let dir = self.path(base)?;
+        if !dir.is_dir().await {
+            return Ok(vec![]);
+        }
+        let mut keys = walk(&dir)
+            .await?
+            .iter()
+            .filter_map(|p| {
+                p.strip_prefix(&self.dir)
+                    .ok()
+                    .and_then(|p| p.to_str())
+                    .map(|s| s.to_string())
+            })
+            .filter(|key| key.starts_with(prefix))
+            .collect::<Vec<_>>();
+        keys.sort();
+        Ok(keys)
+    }
+    async fn delete(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        if path.exists().await {
+            async_std::fs::remove_file(&path)
+                .await
+                .known_error(&format!("fail to remove: {}", path.display()), false)?;
+        }
+        Ok(())
+    }
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(self.path(key)?.is_file().await)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path() {
+        let conn = Connector::new("/tmp/lw-test-artifacts");
+        assert_eq!(
+            conn.path("/39/work/a/b.txt").unwrap(),
+            PathBuf::from("/tmp/lw-test-artifacts/39/work/a/b.txt")
+        );
+        assert!(conn.path("../../x").is_err());
+        assert!(conn.path("39/work/../../../x").is_err());
+        assert_eq!(
+            conn.path("//etc/passwd").unwrap(),
+            PathBuf::from("/tmp/lw-test-artifacts/etc/passwd")
+        );
+    }
+}
@@ -1,4 +1,5 @@
-use crate::error::{KnownErrorsHelper, Result};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::redact;
 
 const PREFIX: &'static str = "LW";
 
@@ -20,6 +21,45 @@ macro_rules! parse_env_opt (
         std::env::var(&name).ok()
     }}
 );
+/// a credential such as a username, which may be given by `<name>_FILE` but is not masked,
+/// since a short one such as "admin" would mask the words everywhere.
+macro_rules! parse_credential {
+    ($name:literal) => {{
+        let name = envname!($name);
+        match secret_opt(&name)? {
+            Some(v) => Ok(v),
+            None => KnownErrors::required(&format!("{} or {}_FILE", name, name)),
+        }
+    }};
+}
+/// a password or a secret key, which `redact` masks.
+macro_rules! parse_secret {
+    ($name:literal) => {{
+        let r: Result<String> = parse_credential!($name);
+        if let Ok(ref v) = r {
+            redact::register(v);
+        }
+        r
+    }};
+}
+
+/// read the credential from `<name>`, or from the file whose path is `<name>_FILE` such as a mounted secret.
+/// the content of the file is trimmed. setting both of them is an error.
+fn secret_opt(name: &str) -> Result<Option<String>> {
+    let file_name = format!("{}_FILE", name);
+    match (std::env::var(name).ok(), std::env::var(&file_name).ok()) {
+        (Some(_), Some(_)) => {
+            KnownErrors::normal(&format!("{} cannot be used with {}", file_name, name), true)
+        }
+        (Some(v), None) => Ok(Some(v)),
+        (None, Some(path)) => {
+            let s = std::fs::read_to_string(&path)
+                .known_error(&format!("fail to read {}: {}", file_name, path), true)?;
+            Ok(Some(s.trim().to_string()))
+        }
+        (None, None) => Ok(None),
+    }
+}
 
 pub fn indir() -> Result<String> {
     parse_env!("INDIR")
@@ -36,11 +76,227 @@ pub fn work_name() -> Result<String> {
 pub fn work_version() -> Result<String> {
     parse_env!("WORK_VERSION")
 }
+/// path of the workflow spec, see `spec::Spec`.
+pub fn spec_opt() -> Option<String> {
+    parse_env_opt!("SPEC")
+}
+/// the number of works which `run-all` runs at once.
+pub fn parallel() -> Result<usize> {
+    match parse_env_opt!("PARALLEL") {
+        None => Ok(1),
+        Some(s) => Ok(s
+            .parse::<usize>()
+            .ok()
+            .filter(|n| 0 < *n)
+            .ok_or("not a positive number")
+            .known_error(&format!("invalid {}", envname!("PARALLEL")), true)?),
+    }
+}
+/// "stop" or "continue", what `run-all` does when a work fails.
+pub fn on_failure() -> Option<String> {
+    parse_env_opt!("ON_FAILURE")
+}
+/// `LW_MAX_ATTEMPTS`, the attempts after which a retryable failure becomes permanent.
+pub fn max_attempts_opt() -> Result<Option<u32>> {
+    match parse_env_opt!("MAX_ATTEMPTS") {
+        None => Ok(None),
+        Some(s) => Ok(Some(
+            s.parse::<u32>()
+                .ok()
+                .filter(|n| 0 < *n)
+                .ok_or("not a positive number")
+                .known_error(&format!("invalid {}", envname!("MAX_ATTEMPTS")), true)?,
+        )),
+    }
+}
+/// the exit code of the executor when the work is not executed. 1 is reserved for the errors.
+macro_rules! parse_exit_code {
+    ($name:literal) => {{
+        match parse_env_opt!($name) {
+            None => Ok(0),
+            Some(s) => match s.parse::<i32>() {
+                Ok(n) if (0..=255).contains(&n) && n != 1 => Ok(n),
+                _ => KnownErrors::normal(
+                    &format!("invalid {}: 0 to 255 except 1", envname!($name)),
+                    true,
+                ),
+            },
+        }
+    }};
+}
+/// `LW_ALREADY_SUCCEEDED_EXIT_CODE`, the exit code of `run` when the work is already succeeded. 0 by default.
+pub fn already_succeeded_exit_code() -> Result<i32> {
+    parse_exit_code!("ALREADY_SUCCEEDED_EXIT_CODE")
+}
+/// `LW_SKIPPED_EXIT_CODE`, the exit code of `run` when the work is skipped by the program. 0 by default.
+pub fn skipped_exit_code() -> Result<i32> {
+    parse_exit_code!("SKIPPED_EXIT_CODE")
+}
+/// `LW_EXIT_CODES`, e.g. "2=permanent,75=retryable,99=skipped".
+pub fn exit_codes_opt() -> Option<String> {
+    parse_env_opt!("EXIT_CODES")
+}
+/// `LW_METADATA_MERGE`, whether metadata.json is merged into the recorded metadata rather than overwrites it.
+/// the merge is done by the store in the same update as `works.<name>`.
+pub fn metadata_merge() -> bool {
+    parse_env_opt!("METADATA_MERGE").map_or(false, |s| s == "true")
+}
+pub fn force() -> bool {
+    parse_env_opt!("FORCE").map_or(false, |s| s == "true")
+}
+/// `LW_OWNER_ID` identifies this executor in leases. `<hostname>:<pid>` if it is not set.
+pub fn owner_id() -> String {
+    parse_env_opt!("OWNER_ID").unwrap_or_else(|| format!("{}:{}", hostname(), std::process::id()))
+}
+pub fn hostname() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| {
+            std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|s| s.trim().to_string())
+        })
+        .filter(|s| !s.is_empty())
+        .unwrap_or("localhost".to_string())
+}
+/// the lease is renewed every 1/3 of it, which must not be too frequent, nor expire before the renewal.
+const LEASE_SECONDS_MIN: u64 = 3;
+pub fn lease_seconds() -> Result<u64> {
+    match parse_env_opt!("LEASE_SECONDS") {
+        None => Ok(60),
+        Some(s) => match s.parse::<u64>() {
+            Ok(n) if LEASE_SECONDS_MIN <= n => Ok(n),
+            _ => KnownErrors::normal(
+                &format!(
+                    "invalid {}: must be {} or more",
+                    envname!("LEASE_SECONDS"),
+                    LEASE_SECONDS_MIN
+                ),
+                true,
+            ),
+        },
+    }
+}
+/// glob patterns of the environment variables passed through to the program, e.g. `PATH,HOME,MY_*`.
+pub fn pass_env() -> Vec<String> {
+    parse_env_opt!("PASS_ENV").map_or(vec![], |s| {
+        s.split(',')
+            .map(|p| p.trim())
+            .filter(|p| !p.is_empty())
+            .map(|p| p.to_string())
+            .collect()
+    })
+}
+/// `LW_CHILD_ENV_<NAME>=<value>` sets `<NAME>=<value>` to the program.
+pub fn child_env() -> Vec<(String, String)> {
+    let mut envs = std::env::vars()
+        .filter_map(|(k, v)| {
+            k.strip_prefix(&envname!("CHILD_ENV_"))
+                .filter(|name| !name.is_empty())
+                .map(|name| (name.to_string(), v.clone()))
+        })
+        .collect::<Vec<_>>();
+    envs.sort();
+    envs
+}
+/// the variables of loadwork itself, which may contain credentials, are never passed through to the program.
+pub fn is_own(name: &str) -> bool {
+    name.starts_with(&envname!(""))
+}
+pub fn timeout_seconds_opt() -> Result<Option<u64>> {
+    match parse_env_opt!("TIMEOUT") {
+        None => Ok(None),
+        Some(s) => Ok(Some(
+            s.parse::<u64>()
+                .known_error(&format!("invalid {}", envname!("TIMEOUT")), true)?,
+        )),
+    }
+}
+pub fn timeout_grace_seconds() -> Result<u64> {
+    match parse_env_opt!("TIMEOUT_GRACE_SECONDS") {
+        None => Ok(10),
+        Some(s) => Ok(s.parse::<u64>().known_error(
+            &format!("invalid {}", envname!("TIMEOUT_GRACE_SECONDS")),
+            true,
+        )?),
+    }
+}
+/// whether a timed out work can be retried. `LW_TIMEOUT_RETRYABLE=false` makes it permanent.
+pub fn timeout_retryable() -> bool {
+    parse_env_opt!("TIMEOUT_RETRYABLE").as_deref() != Some("false")
+}
+pub fn log_max_bytes() -> Result<u64> {
+    match parse_env_opt!("LOG_MAX_BYTES") {
+        None => Ok(10 * 1024 * 1024),
+        Some(s) => Ok(s
+            .parse::<u64>()
+            .known_error(&format!("invalid {}", envname!("LOG_MAX_BYTES")), true)?),
+    }
+}
+pub fn log_tail_lines() -> Result<usize> {
+    match parse_env_opt!("LOG_TAIL_LINES") {
+        None => Ok(20),
+        Some(s) => Ok(s
+            .parse::<usize>()
+            .known_error(&format!("invalid {}", envname!("LOG_TAIL_LINES")), true)?),
+    }
+}
+/// `LW_RETRY_<stage>_<name>`, e.g. `LW_RETRY_ARTIFACT_MAX_ATTEMPTS`.
+pub fn retry_u64_opt(stage: &str, name: &str) -> Result<Option<u64>> {
+    let name = format!("{}_RETRY_{}_{}", PREFIX, stage.to_uppercase(), name);
+    match std::env::var(&name).ok() {
+        None => Ok(None),
+        Some(s) => Ok(Some(
+            s.parse::<u64>()
+                .known_error(&format!("invalid {}", name), true)?,
+        )),
+    }
+}
+/// `LW_RETRY_<stage>_JITTER`, between 0 and 1.
+pub fn retry_jitter_opt(stage: &str) -> Result<Option<f64>> {
+    let name = format!("{}_RETRY_{}_JITTER", PREFIX, stage.to_uppercase());
+    match std::env::var(&name).ok() {
+        None => Ok(None),
+        Some(s) => Ok(Some(
+            s.parse::<f64>()
+                .ok()
+                .filter(|f| (0.0..=1.0).contains(f))
+                .ok_or("not between 0 and 1")
+                .known_error(&format!("invalid {}", name), true)?,
+        )),
+    }
+}
+pub fn record_backend_opt() -> Option<String> {
+    parse_env_opt!("RECORD_BACKEND")
+}
+pub fn mongodb_uri_opt() -> Result<Option<String>> {
+    let uri = secret_opt(&envname!("MONGODB_URI"))?;
+    if let Some(ref uri) = uri {
+        redact::register_uri(uri);
+    }
+    Ok(uri)
+}
+/// names of the set variables which compose the connection string instead of `LW_MONGODB_URI`.
+pub fn mongodb_uri_parts_set() -> Vec<String> {
+    [
+        envname!("MONGODB_HOST"),
+        envname!("MONGODB_PORT"),
+        envname!("MONGODB_USERNAME"),
+        envname!("MONGODB_USERNAME_FILE"),
+        envname!("MONGODB_PASSWORD"),
+        envname!("MONGODB_PASSWORD_FILE"),
+        envname!("MONGODB_OPTIONS"),
+    ]
+    .iter()
+    .filter(|name| std::env::var(name).is_ok())
+    .cloned()
+    .collect()
+}
 pub fn mongodb_username() -> Result<String> {
-    parse_env!("MONGODB_USERNAME")
+    parse_credential!("MONGODB_USERNAME")
 }
 pub fn mongodb_password() -> Result<String> {
-    parse_env!("MONGODB_PASSWORD")
+    parse_secret!("MONGODB_PASSWORD")
 }
 pub fn mongodb_host() -> Result<String> {
     parse_env!("MONGODB_HOST")
@@ -54,15 +310,21 @@ pub fn mongodb_options() -> String {
 pub fn mongodb_database() -> Result<String> {
     parse_env!("MONGODB_DATABASE")
 }
+pub fn mongodb_database_opt() -> Option<String> {
+    parse_env_opt!("MONGODB_DATABASE")
+}
 pub fn mongodb_collection() -> Result<String> {
     parse_env!("MONGODB_COLLECTION")
 }
 
+pub fn artifact_backend_opt() -> Option<String> {
+    parse_env_opt!("ARTIFACT_BACKEND")
+}
 pub fn s3_access_key() -> Result<String> {
-    parse_env!("S3_ACCESS_KEY")
+    parse_credential!("S3_ACCESS_KEY")
 }
 pub fn s3_secret_key() -> Result<String> {
-    parse_env!("S3_SECRET_KEY")
+    parse_secret!("S3_SECRET_KEY")
 }
 pub fn s3_bucket() -> Result<String> {
     parse_env!("S3_BUCKET")
@@ -77,6 +339,35 @@ pub fn s3_path_style() -> Result<bool> {
     parse_env!("S3_PATH_STYLE").map(|s| s == "true")
 }
 
+/// set the variables which are not given for tests, same as `make test-local`.
+/// the records and artifacts are stored in the local directory unless MongoDB and S3 are given,
+/// so that `cargo test` runs without servers.
+#[cfg(test)]
+pub fn set_test_defaults() {
+    let mut defaults = vec![
+        ("TARGET_ID", "test-local"),
+        ("WORK_NAME", "foobar"),
+        ("WORK_VERSION", "1"),
+        ("INDIR", "/tmp/lw-test-local-in"),
+        ("OUTDIR", "/tmp/lw-test-local-out"),
+    ];
+    if parse_env_opt!("MONGODB_HOST").is_none()
+        && parse_env_opt!("MONGODB_URI").is_none()
+        && parse_env_opt!("MONGODB_URI_FILE").is_none()
+    {
+        defaults.push(("RECORD_BACKEND", "file:///tmp/lw-test-local-records"));
+    }
+    if parse_env_opt!("S3_BUCKET").is_none() {
+        defaults.push(("ARTIFACT_BACKEND", "file:///tmp/lw-test-local-artifacts"));
+    }
+    for (name, value) in defaults.into_iter() {
+        let name = format!("{}_{}", PREFIX, name);
+        if std::env::var(&name).is_err() {
+            std::env::set_var(&name, value);
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Depend {
     pub work_name: String,
@@ -131,3 +422,66 @@ pub fn depends() -> Result<Vec<Depend>> {
         .collect();
     Ok(depends)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_opt() {
+        let name = "LW_TEST_SECRET";
+        let file_name = "LW_TEST_SECRET_FILE";
+        let path = "/tmp/lw-test-secret";
+        std::fs::write(path, "  s3cret\n").unwrap();
+        std::env::remove_var(name);
+        std::env::remove_var(file_name);
+        assert_eq!(secret_opt(name).unwrap(), None);
+
+        std::env::set_var(name, "plain");
+        assert_eq!(secret_opt(name).unwrap(), Some("plain".to_string()));
+
+        std::env::set_var(file_name, path);
+        let e = secret_opt(name).unwrap_err();
+        assert_matches::assert_matches!(
+            e.downcast_ref::<KnownErrors>(),
+            Some(KnownErrors::Normal(_, true))
+        );
+
+        std::env::remove_var(name);
+        assert_eq!(secret_opt(name).unwrap(), Some("s3cret".to_string()));
+
+        std::env::set_var(file_name, "/tmp/lw-test-secret-not-found");
+        assert!(secret_opt(name).is_err());
+        std::env::remove_var(file_name);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_lease_seconds() {
+        let name = "LW_LEASE_SECONDS";
+        std::env::remove_var(name);
+        assert_eq!(lease_seconds().unwrap(), 60);
+        std::env::set_var(name, "3");
+        assert_eq!(lease_seconds().unwrap(), 3);
+        for s in ["0", "2", "-1", "x"].iter() {
+            std::env::set_var(name, s);
+            assert!(lease_seconds().is_err(), "{}", s);
+        }
+        std::env::remove_var(name);
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_exit_code() {
+        let name = "LW_ALREADY_SUCCEEDED_EXIT_CODE";
+        std::env::remove_var(name);
+        assert_eq!(already_succeeded_exit_code().unwrap(), 0);
+        std::env::set_var(name, "3");
+        assert_eq!(already_succeeded_exit_code().unwrap(), 3);
+        for s in ["1", "256", "-1", "x"].iter() {
+            std::env::set_var(name, s);
+            assert!(already_succeeded_exit_code().is_err(), "{}", s);
+        }
+        std::env::remove_var(name);
+    }
+}
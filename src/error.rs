@@ -1,19 +1,62 @@
 #[allow(dead_code)]
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync + 'static>>;
 
-#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+/// the messages are redacted when they are displayed or debugged, since they may contain credentials.
+#[derive(thiserror::Error, PartialEq, Eq)]
 pub enum KnownErrors {
-    #[error("{0}")]
+    #[error("{}", crate::redact::redact(.0))]
     Normal(String, bool),
-    #[error("{0} is not set")]
+    #[error("{} is not set", crate::redact::redact(.0))]
     Required(String),
+    /// the program did not finish in time.
+    #[error("{}", crate::redact::redact(.0))]
+    Timeout(String, bool),
+    /// the program was terminated by the signal which the executor received.
+    #[error("{}", crate::redact::redact(.0))]
+    Cancelled(String),
+    /// the program asked to skip the target by its exit code.
+    #[error("{}", crate::redact::redact(.0))]
+    Skipped(String),
 }
+impl std::fmt::Debug for KnownErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        use crate::redact::redact;
+        match self {
+            KnownErrors::Normal(s, permanent) => f
+                .debug_tuple("Normal")
+                .field(&redact(s))
+                .field(permanent)
+                .finish(),
+            KnownErrors::Required(s) => f.debug_tuple("Required").field(&redact(s)).finish(),
+            KnownErrors::Timeout(s, permanent) => f
+                .debug_tuple("Timeout")
+                .field(&redact(s))
+                .field(permanent)
+                .finish(),
+            KnownErrors::Cancelled(s) => f.debug_tuple("Cancelled").field(&redact(s)).finish(),
+            KnownErrors::Skipped(s) => f.debug_tuple("Skipped").field(&redact(s)).finish(),
+        }
+    }
+}
+
 impl KnownErrors {
     #[allow(dead_code)]
     pub fn normal<T>(msg: &str, permanent: bool) -> Result<T> {
         Err(Box::new(KnownErrors::Normal(msg.to_string(), permanent)))
     }
     #[allow(dead_code)]
+    pub fn timeout<T>(msg: &str, permanent: bool) -> Result<T> {
+        Err(Box::new(KnownErrors::Timeout(msg.to_string(), permanent)))
+    }
+    #[allow(dead_code)]
+    pub fn cancelled<T>(msg: &str) -> Result<T> {
+        Err(Box::new(KnownErrors::Cancelled(msg.to_string())))
+    }
+    #[allow(dead_code)]
+    pub fn skipped<T>(msg: &str) -> Result<T> {
+        Err(Box::new(KnownErrors::Skipped(msg.to_string())))
+    }
+    #[allow(dead_code)]
     pub fn required<T>(name: &str) -> Result<T> {
         Err(Box::new(KnownErrors::Required(name.to_string())))
     }
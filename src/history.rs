@@ -0,0 +1,68 @@
+use crate::error::{KnownErrors, Result};
+use crate::record::WorkRecord;
+use crate::redact::redact;
+
+#[derive(Debug, Clone)]
+struct Config {
+    record_connector: crate::record::Connector,
+}
+
+impl Config {
+    pub fn new_from_env() -> Result<Self> {
+        use crate::record;
+        let s = Self {
+            record_connector: record::Connector::new_from_env()?,
+        };
+        Ok(s)
+    }
+}
+
+#[allow(dead_code)]
+pub async fn history_from_env(args: &[String]) -> Result<()> {
+    let config = Config::new_from_env()?;
+    history(args, &config).await
+}
+
+/// print the finished attempts of the target, one per line.
+async fn history(args: &[String], config: &Config) -> Result<()> {
+    let (target_id, work_name) = match args {
+        [target_id] => (target_id, None),
+        [target_id, work_name] => (target_id, Some(work_name.as_str())),
+        _ => return KnownErrors::normal("usage: history <target_id> [work]", true),
+    };
+    let mc = &mut config.record_connector.connect().await?;
+    let history = mc.get_history(target_id, work_name).await?;
+    for w in history.iter() {
+        println!("{}", redact(&format_work_record(w)));
+    }
+    Ok(())
+}
+
+fn format_work_record(w: &WorkRecord) -> String {
+    let datetime = |d: Option<&mongodb::bson::DateTime>| {
+        d.map_or("-".to_string(), |d| d.to_chrono().to_rfc3339())
+    };
+    format!(
+        "{}\t{}\t{}\t{}\t{:?}\tattempt={}\texit={}\tkind={}\thost={}\tartifacts=[{}]\tretries=[{}]\terror={}",
+        datetime(w.started.as_ref()),
+        datetime(Some(&w.updated)),
+        w.name,
+        w.version,
+        w.status,
+        w.attempts,
+        w.exit_code.map_or("-".to_string(), |c| c.to_string()),
+        w.error_kind.map_or("-".to_string(), |k| format!("{:?}", k)),
+        w.host.as_deref().unwrap_or("-"),
+        w.artifacts
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect::<Vec<_>>()
+            .join(";"),
+        w.retries
+            .iter()
+            .map(|(stage, n)| format!("{}:{}", stage, n))
+            .collect::<Vec<_>>()
+            .join(";"),
+        w.error.as_deref().unwrap_or("-"),
+    )
+}
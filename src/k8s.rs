@@ -0,0 +1,439 @@
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::spec::{Spec, WorkSpec};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// the path of the executor in the image built by `Dockerfile`.
+const LOADWORK_BIN: &str = "/loadwork-bullseye-release";
+
+#[derive(Debug, Clone)]
+struct Config {
+    spec: Spec,
+    target_id: String,
+    /// only the work is emitted if it is given.
+    work_name: Option<String>,
+    image: String,
+    namespace: Option<String>,
+    /// the variables added to the ConfigMap, e.g. `LW_MONGODB_HOST`.
+    env: BTreeMap<String, String>,
+    /// the Secret which holds the credentials, e.g. `LW_MONGODB_PASSWORD`.
+    secret: Option<String>,
+}
+
+#[allow(dead_code)]
+pub async fn k8s_manifest_from_env(args: &[String]) -> Result<()> {
+    let mut spec_path = crate::envvar::spec_opt();
+    let mut target_id = None;
+    let mut work_name = None;
+    let mut image = "loadwork:latest".to_string();
+    let mut namespace = None;
+    let mut env = BTreeMap::new();
+    let mut secret = None;
+    let mut args = args;
+    while let Some(arg) = args.first() {
+        let value = args
+            .get(1)
+            .cloned()
+            .ok_or(format!("{} requires a value", arg))
+            .known_error("invalid argument", true)?;
+        match arg.as_str() {
+            "--spec" => spec_path = Some(value),
+            "--target" => target_id = Some(value),
+            "--work" => work_name = Some(value),
+            "--image" => image = value,
+            "--namespace" => namespace = Some(value),
+            "--secret" => secret = Some(value),
+            "--env" => {
+                let (k, v) = value
+                    .split_once('=')
+                    .ok_or("--env <name>=<value>")
+                    .known_error("invalid argument", true)?;
+                env.insert(k.to_string(), v.to_string());
+            }
+            _ => {
+                return KnownErrors::normal(&format!("unknown argument: {}", arg), true);
+            }
+        }
+        args = &args[2..];
+    }
+    let spec_path = spec_path
+        .ok_or("LW_SPEC or --spec is required")
+        .known_error("invalid argument", true)?;
+    let target_id = target_id
+        .ok_or("--target is required")
+        .known_error("invalid argument", true)?;
+    let config = Config {
+        spec: Spec::load(&spec_path)?,
+        target_id,
+        work_name,
+        image,
+        namespace,
+        env,
+        secret,
+    };
+    print!("{}", manifest(&config)?);
+    Ok(())
+}
+
+/// the ConfigMap and the Job of the works for the target, in YAML.
+/// the works run as the init containers of the Job one by one in the topological order,
+/// and the last one runs as its container. each work has its own `emptyDir` volume at `/work`.
+fn manifest(config: &Config) -> Result<String> {
+    let problems = config.spec.validate();
+    if !problems.is_empty() {
+        return KnownErrors::normal(&format!("invalid spec: {}", problems.join("; ")), true);
+    }
+    let works = match config.work_name {
+        Some(ref name) => match config.spec.works.get_key_value(name) {
+            None => {
+                return KnownErrors::normal(
+                    &format!("Work '{}' is not defined in the spec", name),
+                    true,
+                )
+            }
+            Some((name, _)) => vec![name.as_str()],
+        },
+        None => config.spec.order()?,
+    };
+    let name = match config.work_name {
+        Some(ref work_name) => dns_label(&format!("loadwork-{}-{}", config.target_id, work_name)),
+        None => dns_label(&format!("loadwork-{}", config.target_id)),
+    };
+
+    let mut data = config.env.clone();
+    data.insert("LW_TARGET_ID".to_string(), config.target_id.clone());
+    data.insert("LW_INDIR".to_string(), "/work/indir".to_string());
+    data.insert("LW_OUTDIR".to_string(), "/work/outdir".to_string());
+    let config_map = ConfigMap {
+        api_version: "v1",
+        kind: "ConfigMap",
+        metadata: ObjectMeta::new(&name, config.namespace.as_deref()),
+        data,
+    };
+
+    let mut env_from = vec![EnvFromSource {
+        config_map_ref: Some(LocalObjectReference { name: name.clone() }),
+        secret_ref: None,
+    }];
+    if let Some(ref secret) = config.secret {
+        env_from.push(EnvFromSource {
+            config_map_ref: None,
+            secret_ref: Some(LocalObjectReference {
+                name: secret.clone(),
+            }),
+        });
+    }
+    let mut volumes = vec![];
+    let mut containers: Vec<Container> = vec![];
+    for work_name in works.iter() {
+        let work = &config.spec.works[*work_name];
+        let container = container(config, work_name, work, &env_from)?;
+        // the names are unique in the spec, but may not be after `dns_label`.
+        if let Some(i) = containers.iter().position(|c| c.name == container.name) {
+            return KnownErrors::normal(
+                &format!(
+                    "Work '{}' and '{}' have the same container name: {}",
+                    works[i], work_name, container.name
+                ),
+                true,
+            );
+        }
+        volumes.push(Volume {
+            name: container.volume_mounts[0].name.clone(),
+            empty_dir: EmptyDir {},
+        });
+        containers.push(container);
+    }
+    let last = containers.pop().into_iter().collect();
+    let job = Job {
+        api_version: "batch/v1",
+        kind: "Job",
+        metadata: ObjectMeta::new(&name, config.namespace.as_deref()),
+        spec: JobSpec {
+            backoff_limit: 0,
+            template: PodTemplateSpec {
+                metadata: PodMeta {
+                    labels: [("app".to_string(), "loadwork".to_string())]
+                        .iter()
+                        .cloned()
+                        .collect(),
+                },
+                spec: PodSpec {
+                    restart_policy: "Never",
+                    volumes,
+                    init_containers: containers,
+                    containers: last,
+                },
+            },
+        },
+    };
+
+    let mut s = serde_yaml::to_string(&config_map).known_error("fail to serialize", true)?;
+    s.push_str(&serde_yaml::to_string(&job).known_error("fail to serialize", true)?);
+    s.push('\n');
+    Ok(s)
+}
+
+fn container(
+    config: &Config,
+    work_name: &str,
+    work: &WorkSpec,
+    env_from: &[EnvFromSource],
+) -> Result<Container> {
+    if work.command.is_empty() {
+        return KnownErrors::normal(&format!("Work '{}' has no command", work_name), true);
+    }
+    let mut env = vec![
+        EnvVar::new("LW_WORK_NAME", work_name),
+        EnvVar::new("LW_WORK_VERSION", &work.version),
+    ];
+    for d in config.spec.depends(work_name)?.iter() {
+        // `LW_DEPENDS_<work>_<version>` is split at the first '_'.
+        if d.work_name.contains('_') {
+            return KnownErrors::normal(
+                &format!("Work '{}' cannot be in LW_DEPENDS_xxx", d.work_name),
+                true,
+            );
+        }
+        // an empty value is ignored, so ";" depends on the work without artifacts.
+        let artifacts = match d.artifacts.is_empty() {
+            true => ";".to_string(),
+            false => d.artifacts.join(";"),
+        };
+        env.push(EnvVar::new(
+            &format!("LW_DEPENDS_{}_{}", d.work_name, d.work_version),
+            &artifacts,
+        ));
+    }
+    let name = dns_label(work_name);
+    let mut command = vec![
+        LOADWORK_BIN.to_string(),
+        "run".to_string(),
+        "--".to_string(),
+    ];
+    command.extend(work.command.iter().cloned());
+    Ok(Container {
+        name: name.clone(),
+        image: config.image.clone(),
+        volume_mounts: vec![VolumeMount {
+            name: format!("{}-container", name),
+            mount_path: "/work".to_string(),
+        }],
+        env_from: env_from.to_vec(),
+        env,
+        command,
+    })
+}
+
+/// lower case alphanumerics and '-', at most 63 characters.
+fn dns_label(s: &str) -> String {
+    let s = s
+        .to_lowercase()
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | '0'..='9' => c,
+            _ => '-',
+        })
+        .take(63)
+        .collect::<String>();
+    s.trim_matches('-').to_string()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<String>,
+}
+impl ObjectMeta {
+    fn new(name: &str, namespace: Option<&str>) -> Self {
+        Self {
+            name: name.to_string(),
+            namespace: namespace.map(|s| s.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfigMap {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: ObjectMeta,
+    data: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Job {
+    api_version: &'static str,
+    kind: &'static str,
+    metadata: ObjectMeta,
+    spec: JobSpec,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobSpec {
+    backoff_limit: u32,
+    template: PodTemplateSpec,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PodTemplateSpec {
+    metadata: PodMeta,
+    spec: PodSpec,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PodMeta {
+    labels: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PodSpec {
+    restart_policy: &'static str,
+    volumes: Vec<Volume>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    init_containers: Vec<Container>,
+    containers: Vec<Container>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Volume {
+    name: String,
+    empty_dir: EmptyDir,
+}
+
+#[derive(Debug, Serialize)]
+struct EmptyDir {}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Container {
+    name: String,
+    image: String,
+    volume_mounts: Vec<VolumeMount>,
+    env_from: Vec<EnvFromSource>,
+    env: Vec<EnvVar>,
+    command: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct VolumeMount {
+    name: String,
+    mount_path: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct EnvFromSource {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    config_map_ref: Option<LocalObjectReference>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_ref: Option<LocalObjectReference>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct LocalObjectReference {
+    name: String,
+}
+
+#[derive(Debug, Serialize)]
+struct EnvVar {
+    name: String,
+    value: String,
+}
+impl EnvVar {
+    fn new(name: &str, value: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            value: value.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// compare with `tests/k8s/<name>`, which is rewritten if `LW_UPDATE_GOLDEN=true`.
+    fn assert_golden(name: &str, actual: &str) {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/k8s")
+            .join(name);
+        if std::env::var("LW_UPDATE_GOLDEN").as_deref() == Ok("true") {
+            std::fs::write(&path, actual).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(actual, expected, "mismatch: {}", path.display());
+    }
+
+    fn config(work_name: Option<&str>) -> Config {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/k8s/spec.yaml");
+        Config {
+            spec: Spec::load(path.to_str().unwrap()).unwrap(),
+            target_id: "Song_001".to_string(),
+            work_name: work_name.map(|s| s.to_string()),
+            image: "localhost:32000/loadwork:latest".to_string(),
+            namespace: Some("loadwork".to_string()),
+            env: [
+                ("LW_MONGODB_HOST", "mongodb"),
+                ("LW_MONGODB_COLLECTION", "works"),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect(),
+            secret: Some("loadwork-credentials".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_manifest_all() {
+        assert_golden("all.golden.yaml", &manifest(&config(None)).unwrap());
+    }
+
+    #[test]
+    fn test_manifest_work() {
+        assert_golden(
+            "whisper.golden.yaml",
+            &manifest(&config(Some("whisper"))).unwrap(),
+        );
+        assert!(manifest(&config(Some("unknown"))).is_err());
+    }
+
+    #[test]
+    fn test_dns_label() {
+        assert_eq!(dns_label("loadwork-Song_001"), "loadwork-song-001");
+        assert_eq!(dns_label(&"x".repeat(70)).len(), 63);
+    }
+
+    #[test]
+    fn test_manifest_container_name_collision() {
+        let spec = Spec::parse(
+            r#"
+works:
+  stem_split:
+    version: "1"
+    command: [python, split.py]
+  Stem-Split:
+    version: "1"
+    command: [python, split2.py]
+"#,
+        )
+        .unwrap();
+        let config = Config {
+            spec,
+            ..config(None)
+        };
+        let e = manifest(&config).unwrap_err().to_string();
+        assert!(e.contains("'Stem-Split' and 'stem_split'"), "{}", e);
+        assert!(e.contains("stem-split"), "{}", e);
+    }
+}
@@ -1,5 +1,12 @@
 pub mod artifact;
 pub mod envvar;
 pub mod error;
+pub mod history;
+pub mod k8s;
+pub mod migrate;
 pub mod record;
+pub mod redact;
+pub mod retry;
 pub mod run;
+pub mod scan;
+pub mod spec;
@@ -1,15 +1,26 @@
 mod artifact;
 mod envvar;
 mod error;
+mod history;
+mod k8s;
+mod migrate;
 mod record;
+mod redact;
+mod retry;
 mod run;
-use crate::error::Result;
+mod scan;
+mod spec;
 
 fn help(arg0: &str, msg: Option<&str>) {
     match msg {
         Some(s) => println!("{}", s),
         None => {
-            println!("{} run <program> [args]...", arg0);
+            println!(
+                "{} run [--force] [--timeout <seconds>] <program> [args]...",
+                arg0
+            );
+            println!("  --force: run even if the work is already succeeded at the same version.");
+            println!("  --timeout: terminate the program after the seconds. same as LW_TIMEOUT.");
             println!(" envvars:");
             println!("   LW_TARGET_ID: Identity of target resource");
             println!("   LW_WORK_NAME: My work name");
@@ -17,43 +28,108 @@ fn help(arg0: &str, msg: Option<&str>) {
             println!(
                 "   LW_DEPENDS_xxx:  Dependent work names. The xxx is specific to actual work code."
             );
+            println!("   LW_SPEC: path of the workflow spec. optional, the depends of LW_WORK_NAME are taken from it instead of LW_DEPENDS_xxx.");
             println!("   LW_INDIR: ");
             println!("   LW_OUTDIR: ");
+            println!("   LW_FORCE: \"true\" or \"false\". optional, same as --force.");
+            println!(
+                "   LW_OWNER_ID: Identity of this executor. optional, default is <hostname>:<pid>."
+            );
+            println!("   LW_LEASE_SECONDS: optional, default is 60, and at least 3.");
+            println!("   LW_PASS_ENV: comma separated globs of variables passed to the program, e.g. PATH,HOME,MY_*. optional.");
+            println!("   LW_CHILD_ENV_<NAME>: set <NAME> to the program. optional.");
+            println!("   LW_TIMEOUT: seconds. optional, default is no timeout.");
+            println!("   LW_TIMEOUT_GRACE_SECONDS: optional, default is 10.");
+            println!(
+                "   LW_TIMEOUT_RETRYABLE: \"true\" or \"false\". optional, default is \"true\"."
+            );
+            println!("   LW_LOG_MAX_BYTES: optional, default is 10485760.");
+            println!("   LW_LOG_TAIL_LINES: optional, default is 20.");
+            println!("   LW_RETRY_<STAGE>_MAX_ATTEMPTS, LW_RETRY_<STAGE>_BASE_DELAY_MS, LW_RETRY_<STAGE>_MAX_DELAY_MS, LW_RETRY_<STAGE>_JITTER:");
+            println!("     retry policy of RECORD, ARTIFACT and PROGRAM. optional, default attempts are 3, 3 and 1.");
+            println!("   LW_EXIT_CODES: status of exit codes, e.g. \"2=permanent,75=retryable,99=skipped,sig15=retryable\". optional, others are permanent.");
+            println!("   LW_METADATA_MERGE: \"true\" or \"false\". optional, default is \"false\" which overwrites the metadata.");
+            println!("   LW_MAX_ATTEMPTS: a retryable failure at the n-th attempt of the version becomes permanent. optional, default is unlimited.");
+            println!("   LW_ALREADY_SUCCEEDED_EXIT_CODE: the exit code when the work is already succeeded and not executed. optional, default is 0.");
+            println!("   LW_SKIPPED_EXIT_CODE: the exit code when the work is skipped by the program, now or before. optional, default is 0.");
+            println!("     both are 0 to 255 except 1, which is of the errors.");
             println!("");
-            println!("   LW_MONGODB_URI: http[s]://<username>:<password>@<hostname>[:port]");
-            println!("   LW_MONGODB_DATABASE: ");
+            println!("   LW_RECORD_BACKEND: \"mongodb\" or \"file:///<dir>\". optional, default is \"mongodb\".");
+            println!("   LW_MONGODB_URI: mongodb[+srv]://[<username>:<password>@]<hostname>[:port][,...]/[database][?options]");
+            println!("   LW_MONGODB_HOST, LW_MONGODB_PORT, LW_MONGODB_USERNAME, LW_MONGODB_PASSWORD, LW_MONGODB_OPTIONS: used if LW_MONGODB_URI is not set.");
+            println!("   LW_MONGODB_DATABASE: optional if LW_MONGODB_URI specifies the database.");
             println!("   LW_MONGODB_COLLECTION: ");
-            println!("   LW_MONGODB_URI: ");
+            println!("   LW_MONGODB_URI, LW_MONGODB_USERNAME, LW_MONGODB_PASSWORD, LW_S3_ACCESS_KEY and LW_S3_SECRET_KEY");
+            println!("     can be read from the file specified by the name suffixed by _FILE, e.g. LW_S3_SECRET_KEY_FILE.");
             println!("");
+            println!(
+                "   LW_ARTIFACT_BACKEND: \"s3\" or \"file:///<dir>\". optional, default is \"s3\"."
+            );
             println!("   LW_S3_ACCESS_KEY: ");
             println!("   LW_S3_SECRET_KEY: ");
             println!("   LW_S3_BUCKET: ");
             println!("   LW_S3_REGION: optional.");
             println!("   LW_S3_ENDPOINT: optional.");
             println!("   LW_S3_PATH_STYLE: \"true\" of \"false\". optional, default is \"true\".");
+            println!(
+                "{} run-all [--force] [--spec <path>] [--parallel <n>] [--on-failure stop|continue] <target_id>",
+                arg0
+            );
+            println!("  run the works of the spec for the target in the topological order.");
+            println!("  each work runs in ${{LW_INDIR}}/<work> and ${{LW_OUTDIR}}/<work> with its command, version and depends in the spec.");
+            println!(" envvars:");
+            println!("   LW_SPEC: same as --spec.");
+            println!("   LW_PARALLEL: the number of works run at once. optional, default is 1.");
+            println!("   LW_ON_FAILURE: \"stop\" or \"continue\". optional, default is \"stop\".");
+            println!("     \"stop\" starts no more works after a failure, \"continue\" runs the works which do not depend on the failed ones.");
+            println!("   the others except for LW_TARGET_ID, LW_WORK_NAME, LW_WORK_VERSION and LW_DEPENDS_xxx are same as run.");
             println!("{} scan", arg0);
+            println!("  print target ids whose depends are all succeeded and LW_WORK_NAME is not done yet.");
+            println!(" envvars:");
+            println!(
+                "   LW_WORK_NAME, LW_DEPENDS_xxx, LW_SPEC and LW_MONGODB_xxx are same as run."
+            );
+            println!("{} history <target_id> [work]", arg0);
+            println!("  print the finished attempts of the target.");
+            println!("{} migrate", arg0);
+            println!("  backfill the top-level metadata.<work> of the records written by the old versions.");
+            println!("{} validate <spec>", arg0);
+            println!("  check the workflow spec for undefined works, version mismatches, missing artifacts and cycles.");
+            println!("{} k8s-manifest [--spec <path>] --target <target_id> [--work <name>] [--image <image>] [--namespace <ns>] [--secret <name>] [--env <name>=<value>]...", arg0);
+            println!("  print the ConfigMap and the Job which run the works of the spec for the target, without connecting to a cluster.");
+            println!("  --secret: the Secret of the credentials, e.g. LW_MONGODB_PASSWORD. --env: the variable added to the ConfigMap.");
         }
     }
     std::process::exit(0);
 }
 
 #[async_std::main]
-async fn main() -> Result<()> {
+async fn main() {
     let args: Vec<String> = std::env::args().collect();
     if args.len() < 2 {
         help(&args[0], None);
     }
     let r = match args[1].as_str() {
-        "run" => crate::run::run_from_env(&args[2..]).await,
-        "scan" => Ok(()),
+        "run" => crate::run::run_from_env(&args[2..]).await.map(|code| {
+            if code != 0 {
+                std::process::exit(code);
+            }
+        }),
+        "run-all" => crate::run::run_all_from_env(&args[2..]).await,
+        "scan" => crate::scan::scan_from_env(&args[2..]).await,
+        "history" => crate::history::history_from_env(&args[2..]).await,
+        "migrate" => crate::migrate::migrate_from_env(&args[2..]).await,
+        "validate" => crate::spec::validate_from_env(&args[2..]).await,
+        "k8s-manifest" => crate::k8s::k8s_manifest_from_env(&args[2..]).await,
         _ => {
             help(&args[0], Some(&format!("unkown subcommand: {}", args[1])));
             Ok(())
         }
     };
+    // the error is not returned, since its Debug form which Rust prints may not be redacted.
     if let Err(ref e) = r {
-        println!("{}", e.to_string());
+        println!("{}", crate::redact::redact(&e.to_string()));
         //e.backtrace().map(|bt| println!("{}", bt));
+        std::process::exit(1);
     }
-    r
 }
@@ -0,0 +1,33 @@
+use crate::error::{KnownErrors, Result};
+
+#[derive(Debug, Clone)]
+struct Config {
+    record_connector: crate::record::Connector,
+}
+
+impl Config {
+    pub fn new_from_env() -> Result<Self> {
+        use crate::record;
+        let s = Self {
+            record_connector: record::Connector::new_from_env()?,
+        };
+        Ok(s)
+    }
+}
+
+#[allow(dead_code)]
+pub async fn migrate_from_env(args: &[String]) -> Result<()> {
+    let config = Config::new_from_env()?;
+    migrate(args, &config).await
+}
+
+/// update the records written by the old versions. it can be run any number of times.
+async fn migrate(args: &[String], config: &Config) -> Result<()> {
+    if !args.is_empty() {
+        return KnownErrors::normal("usage: migrate", true);
+    }
+    let mc = &mut config.record_connector.connect().await?;
+    let n = mc.backfill_metadata().await?;
+    println!("metadata.<work> is backfilled: {} records", n);
+    Ok(())
+}
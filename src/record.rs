@@ -7,177 +7,296 @@ use async_std::{
     path::Path,
 };
 //use chrono;
+use async_trait::async_trait;
+use mongodb::bson;
 pub use mongodb::bson::{doc, Document};
-use mongodb::{bson, options::UpdateOptions};
 use serde;
 
-#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum WorkStatus {
     NotStarted = 0,
     Succeeded = 1,
+    Running = 2,
+    /// the program asked to skip the target, which is neither succeeded nor failed.
+    Skipped = 3,
     FailRetryable = 10,
     FailPermanent = 11,
 }
 
+/// what caused the failure of the work, which is recorded with the status.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ErrorKind {
+    /// the program failed by its exit status or `result.json`.
+    Exit,
+    /// the program did not finish within `LW_TIMEOUT`.
+    Timeout,
+    /// the executor received a signal.
+    Cancelled,
+    /// the executor failed itself, e.g. to download the depends or to upload the artifacts.
+    Executor,
+}
+
 pub type Metadata = Document;
 
+/// The executor which is running a work holds the lease until `expires`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Lease {
+    pub owner: String,
+    pub expires: bson::DateTime,
+}
+impl Lease {
+    pub fn new(owner: &str, duration: std::time::Duration) -> Self {
+        let expires = chrono::Utc::now()
+            + chrono::Duration::from_std(duration).unwrap_or_else(|_| chrono::Duration::zero());
+        Self {
+            owner: owner.to_string(),
+            expires: bson::DateTime::from_chrono(expires),
+        }
+    }
+}
+
+/// An uploaded artifact. `name` is the path relative to `$LW_OUTDIR/artifacts`.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(from = "ArtifactRepr")]
+pub struct Artifact {
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+    pub content_type: String,
+    pub uploaded: bson::DateTime,
+}
+
+/// the records written before checksums were introduced have only the names of artifacts.
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum ArtifactRepr {
+    Name(String),
+    Entry {
+        name: String,
+        size: u64,
+        sha256: String,
+        content_type: String,
+        uploaded: bson::DateTime,
+    },
+}
+impl From<ArtifactRepr> for Artifact {
+    fn from(r: ArtifactRepr) -> Self {
+        match r {
+            ArtifactRepr::Name(name) => Artifact {
+                name,
+                size: 0,
+                sha256: String::new(),
+                content_type: String::new(),
+                uploaded: bson::DateTime::from_millis(0),
+            },
+            ArtifactRepr::Entry {
+                name,
+                size,
+                sha256,
+                content_type,
+                uploaded,
+            } => Artifact {
+                name,
+                size,
+                sha256,
+                content_type,
+                uploaded,
+            },
+        }
+    }
+}
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct WorkRecord {
     pub name: String,
     pub version: String,
     pub status: WorkStatus,
     pub error: Option<String>,
+    /// what caused the failure, which is `None` unless it failed.
+    #[serde(default)]
+    pub error_kind: Option<ErrorKind>,
     //pub updated: chrono::NaiveDateTime,
     pub updated: bson::DateTime,
-    pub artifacts: Vec<String>,
+    pub artifacts: Vec<Artifact>,
     pub metadata: Metadata,
+    #[serde(default)]
+    pub lease: Option<Lease>,
+    #[serde(default)]
+    pub started: Option<bson::DateTime>,
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+    #[serde(default)]
+    pub host: Option<String>,
+    /// the number of the retried operations in the attempt by stage, e.g. `{"artifact": 1}`.
+    #[serde(default, skip_serializing_if = "Retries::is_empty")]
+    pub retries: Retries,
+    /// the number of the finished attempts at the version, which `update_work_record` increments.
+    #[serde(default)]
+    pub attempts: u32,
+    /// the human-readable message which the program gave in `result.json`.
+    #[serde(default)]
+    pub message: Option<String>,
 }
 
+pub type Retries = std::collections::BTreeMap<String, u32>;
+
 pub type WorkRecordMap = HashMap<String, WorkRecord>;
 
+/// Each finished attempt is appended to the `history` array of the document.
+/// It is not a part of `WorkflowRecord` because it grows up to `HISTORY_MAX` attempts.
+pub type History = Vec<WorkRecord>;
+
+/// the number of the latest attempts kept in the history, so that the document stays within 16 MB of MongoDB.
+pub const HISTORY_MAX: usize = 1000;
+
 #[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
 pub struct WorkflowRecord {
     pub id: String,
     pub works: WorkRecordMap,
 }
 
-fn db_key(target_id: &str) -> Document {
-    doc! { "id": target_id.to_string() }
+mod file;
+mod mongo;
+
+/// Where workflow records are stored.
+/// MongoDB is selected by default, and `LW_RECORD_BACKEND=file:///path/to/dir` selects the local directory.
+#[async_trait]
+pub trait RecordStore: Send {
+    #[allow(dead_code)]
+    async fn delete_all(&mut self) -> Result<()>;
+    #[cfg(test)]
+    async fn get_raw_document(&mut self, target_id: &str) -> Result<Option<Document>>;
+    /// returns the record of the target, inserting an empty one if it does not exist.
+    async fn get_or_default(&mut self, target_id: &str) -> Result<WorkflowRecord>;
+    /// returns ids of the targets which are ready to process `work_name`.
+    async fn find_ready(
+        &mut self,
+        work_name: &str,
+        depends: &[crate::envvar::Depend],
+    ) -> Result<Vec<String>>;
+    /// set `work_record`, whose status is `Running` and which holds the lease, if the work is claimable.
+    /// the recorded metadata is kept instead of `work_record.metadata`, so that it is merged into at the end.
+    /// returns false if another executor holds the live lease.
+    async fn claim_work(&mut self, target_id: &str, work_record: &WorkRecord) -> Result<bool>;
+    /// extend the lease held by `lease.owner`. returns false if the lease is lost.
+    async fn renew_lease(
+        &mut self,
+        target_id: &str,
+        work_name: &str,
+        lease: &Lease,
+    ) -> Result<bool>;
+    /// set the work record and append it to the history, and its metadata to the top-level `metadata.<name>`.
+    /// `attempts` of the stored work is incremented atomically, and the history has `work_record.attempts + 1`.
+    /// nothing is done if the history has the same attempt and `updated`, so that it can be retried.
+    /// the oldest attempts beyond `HISTORY_MAX` are dropped from the history.
+    /// with `merge_metadata`, the metadata is merged recursively into the recorded one in the same update,
+    /// while the history has the metadata of the attempt.
+    async fn update_work_record(
+        &mut self,
+        target_id: &str,
+        work_record: &WorkRecord,
+        merge_metadata: bool,
+    ) -> Result<()>;
+    /// returns the finished attempts in order. only of `work_name` if it is given.
+    async fn get_history(&mut self, target_id: &str, work_name: Option<&str>) -> Result<History>;
+    /// set the top-level fields of the workflow record, which are checked by `check_fields`.
+    async fn set_fields(&mut self, target_id: &str, fields: &Document) -> Result<()>;
+    /// set the top-level `metadata.<name>` of all records from `works.<name>.metadata` where it is missing,
+    /// for the records written before it was introduced. returns the number of the updated records.
+    async fn backfill_metadata(&mut self) -> Result<u64>;
+}
+
+/// the top-level fields which the record uses itself.
+const RESERVED_FIELDS: &[&str] = &["_id", "id", "works", "history", "metadata"];
+
+/// the fields given by the program must not overwrite the record or be taken as operators or paths.
+pub fn check_fields(fields: &Document) -> Result<()> {
+    for k in fields.keys() {
+        if k.is_empty()
+            || k.starts_with('$')
+            || k.contains('.')
+            || RESERVED_FIELDS.contains(&k.as_str())
+        {
+            return KnownErrors::normal(&format!("invalid field: {}", k), true);
+        }
+    }
+    Ok(())
 }
 
+pub type Connect = Box<dyn RecordStore>;
+
 #[derive(Debug, Clone)]
-pub struct Connector {
-    urlbase: String,
-    options: String,
-    database: String,
-    collection: String,
+pub enum Connector {
+    MongoDb(mongo::Connector),
+    File(file::Connector),
 }
 impl Connector {
     pub fn new_from_env() -> Result<Self> {
         use crate::envvar;
-        let host = envvar::mongodb_host()?;
-        let port = envvar::mongodb_port();
-        let user = envvar::mongodb_username()?;
-        let pass = envvar::mongodb_password()?;
-        let urlbase = format!("mongodb://{}:{}@{}:{}", user, pass, host, port);
-        let options = envvar::mongodb_options();
-        let database = envvar::mongodb_database()?;
-        let collection = envvar::mongodb_collection()?;
-        //println!("mongodb: urlbase={}, db={}, coll={}", urlbase, database, collection);
-        Ok(Connector::new(urlbase, options, database, collection))
-    }
-    pub fn new(urlbase: String, options: String, database: String, collection: String) -> Self {
-        Self {
-            urlbase: urlbase,
-            options: options,
-            database: database,
-            collection: collection,
-        }
+        let c = match envvar::record_backend_opt() {
+            None => Connector::MongoDb(mongo::Connector::new_from_env()?),
+            Some(s) if s == "mongodb" => Connector::MongoDb(mongo::Connector::new_from_env()?),
+            Some(s) => match s.strip_prefix("file://") {
+                Some(dir) => Connector::File(file::Connector::new(dir)),
+                None => {
+                    return KnownErrors::normal(&format!("unknown record backend: {}", s), true)
+                }
+            },
+        };
+        Ok(c)
     }
     pub async fn connect(&self) -> Result<Connect> {
-        Connect::new(
-            &self.urlbase,
-            &self.options,
-            &self.database,
-            &self.collection,
-        )
-        .await
-    }
-}
-
-pub struct Connect {
-    coll: mongodb::Collection<Document>,
-}
-
-impl Connect {
-    #[allow(dead_code)]
-    pub async fn new_from_env() -> Result<Self> {
-        let c = Connector::new_from_env()?;
-        c.connect().await
-    }
-    pub async fn new(
-        urlbase: &str,
-        options: &str,
-        database: &str,
-        collection: &str,
-    ) -> Result<Self> {
-        let url = format!("{}/{}?{}", urlbase, database, options);
-        //println!("mongodb: connectiong to '{}'", url);
-        let mongodb_client = mongodb::Client::with_uri_str(&url)
-            .await
-            .known_error(&format!("fail to connect: {}", url.to_string()), true)?;
-        let mongodb_coll = mongodb_client.database(database).collection(collection);
-        Ok(Self { coll: mongodb_coll })
+        let c: Connect = match self {
+            Connector::MongoDb(c) => Box::new(c.connect().await?),
+            Connector::File(c) => Box::new(c.connect().await?),
+        };
+        Ok(c)
     }
-
-    #[allow(dead_code)]
-    pub async fn delete_all(&mut self) -> Result<()> {
-        let _ = self.coll.drop(None).await?;
-        Ok(())
+    /// the number of the retried operations. the local directory is not retried.
+    pub fn retried(&self) -> u32 {
+        match self {
+            Connector::MongoDb(c) => c.retried(),
+            Connector::File(_) => 0,
+        }
     }
+}
 
-    #[cfg(test)]
-    pub async fn get_raw_document(&mut self, target_id: &str) -> Result<Option<Document>> {
-        let key = db_key(target_id);
-        let opt_doc = self
-            .coll
-            .find_one(key.clone(), None)
-            .await
-            .known_error("fail to find", true)?;
-        Ok(opt_doc)
+/// whether `work` is in the state that `run` can claim it by `owner`.
+fn is_claimable(work: Option<&WorkRecord>, owner: &str, now: bson::DateTime) -> bool {
+    match work {
+        None => true,
+        Some(w) if w.status != WorkStatus::Running => true,
+        Some(w) => match w.lease {
+            None => false,
+            Some(ref lease) => lease.expires < now || lease.owner == owner,
+        },
     }
-    pub async fn get_or_default(&mut self, target_id: &str) -> Result<WorkflowRecord> {
-        let key = db_key(target_id);
-        let workflow_record = WorkflowRecord {
-            id: target_id.to_string(),
-            works: WorkRecordMap::new(),
-        };
-        let doc = bson::to_document(&workflow_record)?;
-        self.coll
-            .update_one(
-                key.clone(),
-                doc! { "$setOnInsert": doc },
-                UpdateOptions::builder().upsert(true).build(),
-            )
-            .await
-            .known_error("fail to upsert", true)?;
+}
 
-        let opt_doc = self
-            .coll
-            .find_one(key.clone(), None)
-            .await
-            .known_error("fail to find", false)?;
-        let doc = match opt_doc {
-            Some(doc) => Ok(doc),
-            None => KnownErrors::normal::<Document>("no document is found", true),
-        }?;
-        println!("load WorkflowResult: {}", doc);
-        let workflow_record = bson::from_document::<WorkflowRecord>(doc)?;
-        Ok(workflow_record)
-    }
-    pub async fn update_work_record(
-        &mut self,
-        target_id: &str,
-        work_record: &WorkRecord,
-    ) -> Result<()> {
-        let key = db_key(target_id);
-
-        //let mut work_record = work_record_.clone();
-        let work_record_doc =
-            bson::to_document(&work_record).known_error("fail to serialize WorkRecord", true)?;
-        //println!("save WorkRecord: {}", work_record_doc); caution this may be too long
-
-        let _ = self
-            .coll
-            .update_one(
-                key.clone(),
-                doc! { "$set": { &format!("works.{}", &work_record.name) : work_record_doc } },
-                None,
-            )
-            .await
-            .known_error("fail to update work record", true)?;
-        Ok(())
-    }
+/// whether all `depends` are succeeded at the required version
+/// and the work `work_name` is not recorded yet, failed retryably or its lease is expired.
+fn is_ready(
+    workflow_record: &WorkflowRecord,
+    work_name: &str,
+    depends: &[crate::envvar::Depend],
+    now: bson::DateTime,
+) -> bool {
+    let depends_ok = depends
+        .iter()
+        .all(|dep| match workflow_record.works.get(&dep.work_name) {
+            Some(w) => w.status == WorkStatus::Succeeded && w.version == dep.work_version,
+            None => false,
+        });
+    depends_ok
+        && match workflow_record.works.get(work_name) {
+            None => true,
+            Some(w) => match w.status {
+                WorkStatus::FailRetryable => true,
+                // as `ready_filter` of MongoDB, a running work without the lease is not ready.
+                WorkStatus::Running => w.lease.as_ref().is_some_and(|l| l.expires < now),
+                _ => false,
+            },
+        }
 }
 
 pub async fn write_workflow_record<P: AsRef<async_std::path::Path>>(
@@ -207,6 +326,41 @@ pub async fn write<W: Write + std::marker::Unpin>(
     Ok(())
 }
 
+/// merge `other` into `base` recursively. the values which are not documents are overwritten.
+pub fn merge_metadata(base: &mut Metadata, other: Metadata) {
+    for (k, v) in other.into_iter() {
+        match (base.get_mut(&k), v) {
+            (Some(bson::Bson::Document(b)), bson::Bson::Document(o)) => merge_metadata(b, o),
+            (_, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+/// the dotted paths under `prefix` and the values of the leaves of `metadata`, with which it is merged by `$set`.
+/// the values which are not documents are leaves, and the empty documents are skipped.
+/// the keys must be usable in the paths.
+pub fn metadata_paths(prefix: &str, metadata: &Metadata) -> Result<Document> {
+    let mut paths = Document::new();
+    for (k, v) in metadata.iter() {
+        if k.is_empty() || k.starts_with('$') || k.contains('.') {
+            return KnownErrors::normal(
+                &format!("the key of metadata can not be merged: {:?}", k),
+                true,
+            );
+        }
+        let path = format!("{}.{}", prefix, k);
+        match v {
+            bson::Bson::Document(d) => paths.extend(metadata_paths(&path, d)?),
+            v => {
+                paths.insert(path, v.clone());
+            }
+        }
+    }
+    Ok(paths)
+}
+
 pub async fn read_metadata_or_empty<P: AsRef<Path>>(path: P) -> Result<Metadata> {
     let path = path.as_ref();
     let metadata = match path.exists().await {
@@ -248,11 +402,12 @@ mod tests {
         pub conn: Connect,
     }
     async fn insert() -> Result<Insert> {
+        envvar::set_test_defaults();
         let target_id = envvar::target_id()?;
         let work_name = envvar::work_name()?;
         let work_version = envvar::work_version()?;
 
-        let mut conn = Connect::new_from_env().await?;
+        let mut conn = Connector::new_from_env()?.connect().await?;
         assert_matches!(conn.delete_all().await, Ok(()));
 
         let inserted = conn.get_or_default(&target_id).await; //insert
@@ -266,6 +421,7 @@ mod tests {
     }
 
     #[async_std::test]
+    #[serial]
     async fn test_insert() -> Result<()> {
         let _ = insert().await?;
         Ok(())
@@ -291,13 +447,21 @@ mod tests {
             version: ins.work_version.clone(),
             status: WorkStatus::Succeeded,
             error: None,
+            error_kind: None,
             updated: bson::DateTime::from(chrono::Utc::now()),
             metadata: doc! { "hello": "world" },
             artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
         };
         assert_matches!(
             ins.conn
-                .update_work_record(&ins.target_id, &work_record)
+                .update_work_record(&ins.target_id, &work_record, false)
                 .await,
             Ok(())
         );
@@ -357,13 +521,21 @@ mod tests {
             version: ins.work_version.clone(),
             status: WorkStatus::Succeeded,
             error: None,
+            error_kind: None,
             updated: bson::DateTime::from(chrono::Utc::now()),
             metadata: doc! { "hello": "world" },
             artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
         };
         assert_matches!(
             ins.conn
-                .update_work_record(&ins.target_id, &work_record)
+                .update_work_record(&ins.target_id, &work_record, false)
                 .await,
             Ok(())
         );
@@ -391,4 +563,385 @@ mod tests {
         assert!(work2_updated.le(&t1));
         Ok(())
     }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_find_ready() -> Result<()> {
+        let mut ins = insert().await?;
+        let other_id = format!("{}-other", ins.target_id);
+        assert_matches!(ins.conn.get_or_default(&other_id).await, Ok(_));
+
+        let depend = envvar::Depend {
+            work_name: "depend".to_string(),
+            work_version: "tmp".to_string(),
+            artifacts: vec![],
+        };
+        let depend_record = |status: WorkStatus| WorkRecord {
+            name: depend.work_name.clone(),
+            version: depend.work_version.clone(),
+            status,
+            error: None,
+            error_kind: None,
+            updated: bson::DateTime::from(chrono::Utc::now()),
+            metadata: Metadata::new(),
+            artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
+        };
+        let depends = vec![depend.clone()];
+
+        // nothing is ready while the depend is not succeeded
+        let r = ins.conn.find_ready(&ins.work_name, &depends).await;
+        assert_matches!(r, Ok(_));
+        assert_eq!(r.unwrap().len(), 0);
+
+        for id in [&ins.target_id, &other_id] {
+            assert_matches!(
+                ins.conn
+                    .update_work_record(id, &depend_record(WorkStatus::Succeeded), false)
+                    .await,
+                Ok(())
+            );
+        }
+        let r = ins.conn.find_ready(&ins.work_name, &depends).await;
+        assert_matches!(r, Ok(_));
+        assert_eq!(r.unwrap(), vec![ins.target_id.clone(), other_id.clone()]);
+
+        // succeeded target is not ready anymore, retryable one is ready again
+        let (work_name, work_version) = (ins.work_name.clone(), ins.work_version.clone());
+        let my_record = |status: WorkStatus| WorkRecord {
+            name: work_name.clone(),
+            version: work_version.clone(),
+            status,
+            error: None,
+            error_kind: None,
+            updated: bson::DateTime::from(chrono::Utc::now()),
+            metadata: Metadata::new(),
+            artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
+        };
+        assert_matches!(
+            ins.conn
+                .update_work_record(&ins.target_id, &my_record(WorkStatus::Succeeded), false)
+                .await,
+            Ok(())
+        );
+        assert_matches!(
+            ins.conn
+                .update_work_record(&other_id, &my_record(WorkStatus::FailRetryable), false)
+                .await,
+            Ok(())
+        );
+        let r = ins.conn.find_ready(&ins.work_name, &depends).await;
+        assert_matches!(r, Ok(_));
+        assert_eq!(r.unwrap(), vec![other_id.clone()]);
+
+        // running one is not ready without the lease, such as recorded by old versions,
+        // and is ready after its lease is expired
+        let running = WorkRecord {
+            attempts: 1,
+            ..my_record(WorkStatus::Running)
+        };
+        assert_matches!(
+            ins.conn
+                .update_work_record(&other_id, &running, false)
+                .await,
+            Ok(())
+        );
+        let r = ins.conn.find_ready(&ins.work_name, &depends).await;
+        assert_matches!(r, Ok(_));
+        assert_eq!(r.unwrap().len(), 0);
+        let expired = WorkRecord {
+            lease: Some(Lease {
+                owner: "alice".to_string(),
+                expires: bson::DateTime::from_millis(0),
+            }),
+            attempts: 2,
+            ..running
+        };
+        assert_matches!(
+            ins.conn
+                .update_work_record(&other_id, &expired, false)
+                .await,
+            Ok(())
+        );
+        let r = ins.conn.find_ready(&ins.work_name, &depends).await;
+        assert_matches!(r, Ok(_));
+        assert_eq!(r.unwrap(), vec![other_id.clone()]);
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_claim_work() -> Result<()> {
+        let mut ins = insert().await?;
+        let running_record = |owner: &str, secs: i64| WorkRecord {
+            name: ins.work_name.clone(),
+            version: ins.work_version.clone(),
+            status: WorkStatus::Running,
+            error: None,
+            error_kind: None,
+            updated: bson::DateTime::from(chrono::Utc::now()),
+            metadata: Metadata::new(),
+            artifacts: vec![],
+            lease: Some(Lease {
+                owner: owner.to_string(),
+                expires: bson::DateTime::from(chrono::Utc::now() + chrono::Duration::seconds(secs)),
+            }),
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
+        };
+        let alice = running_record("alice", 60);
+        let bob = running_record("bob", 60);
+
+        let r = ins.conn.claim_work(&ins.target_id, &alice).await;
+        assert_matches!(r, Ok(true));
+        let r = ins.conn.claim_work(&ins.target_id, &bob).await;
+        assert_matches!(r, Ok(false));
+
+        let r = ins
+            .conn
+            .renew_lease(&ins.target_id, &ins.work_name, bob.lease.as_ref().unwrap())
+            .await;
+        assert_matches!(r, Ok(false));
+        let r = ins
+            .conn
+            .renew_lease(
+                &ins.target_id,
+                &ins.work_name,
+                &Lease::new("alice", std::time::Duration::from_secs(0)),
+            )
+            .await;
+        assert_matches!(r, Ok(true));
+
+        // alice's lease is expired now
+        async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+        let r = ins.conn.claim_work(&ins.target_id, &bob).await;
+        assert_matches!(r, Ok(true));
+
+        let wf = ins.conn.get_or_default(&ins.target_id).await?;
+        let work = wf.works.get(&ins.work_name).unwrap();
+        assert_eq!(work.status, WorkStatus::Running);
+        assert_eq!(work.lease.as_ref().unwrap().owner, "bob");
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_history() -> Result<()> {
+        let mut ins = insert().await?;
+        let work_record = |name: &str, status: WorkStatus, error: Option<&str>| WorkRecord {
+            name: name.to_string(),
+            version: "1".to_string(),
+            status,
+            error: error.map(|s| s.to_string()),
+            error_kind: None,
+            updated: bson::DateTime::from(chrono::Utc::now()),
+            metadata: Metadata::new(),
+            artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
+        };
+        let records = [
+            work_record(&ins.work_name, WorkStatus::FailRetryable, Some("first")),
+            work_record("other", WorkStatus::Succeeded, None),
+            // the claimed record carries the stored attempts, as run does.
+            WorkRecord {
+                attempts: 1,
+                ..work_record(&ins.work_name, WorkStatus::Succeeded, None)
+            },
+        ];
+        for w in records.iter() {
+            assert_matches!(
+                ins.conn.update_work_record(&ins.target_id, w, false).await,
+                Ok(())
+            );
+        }
+
+        let r = ins.conn.get_history(&ins.target_id, None).await;
+        assert_matches!(r, Ok(_));
+        let history = r.unwrap();
+        assert_eq!(history.len(), 3);
+
+        let r = ins
+            .conn
+            .get_history(&ins.target_id, Some(&ins.work_name))
+            .await;
+        assert_matches!(r, Ok(_));
+        let history = r.unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0].status, WorkStatus::FailRetryable);
+        assert_eq!(history[0].error, Some("first".to_string()));
+        assert_eq!(history[1].status, WorkStatus::Succeeded);
+        assert_eq!(history[0].attempts, 1);
+        assert_eq!(history[1].attempts, 2);
+
+        // the last one is also in works, and the attempts are incremented
+        let wf = ins.conn.get_or_default(&ins.target_id).await?;
+        let w = wf.works.get(&ins.work_name).unwrap();
+        assert_eq!(w.status, WorkStatus::Succeeded);
+        assert_eq!(w.attempts, 2);
+
+        // the same attempt is recorded once even if it is retried
+        assert_matches!(
+            ins.conn
+                .update_work_record(&ins.target_id, &records[2], false)
+                .await,
+            Ok(())
+        );
+        let history = ins
+            .conn
+            .get_history(&ins.target_id, Some(&ins.work_name))
+            .await?;
+        assert_eq!(history.len(), 2);
+        let wf = ins.conn.get_or_default(&ins.target_id).await?;
+        assert_eq!(wf.works.get(&ins.work_name).unwrap().attempts, 2);
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_metadata() -> Result<()> {
+        let mut ins = insert().await?;
+        let work_record = WorkRecord {
+            name: ins.work_name.clone(),
+            version: ins.work_version.clone(),
+            status: WorkStatus::Succeeded,
+            error: None,
+            error_kind: None,
+            updated: bson::DateTime::from(chrono::Utc::now()),
+            metadata: doc! { "a": { "x": 1 } },
+            artifacts: vec![],
+            lease: None,
+            started: None,
+            exit_code: None,
+            host: None,
+            retries: Retries::new(),
+            attempts: 0,
+            message: None,
+        };
+        ins.conn
+            .update_work_record(&ins.target_id, &work_record, false)
+            .await?;
+        let doc = ins.conn.get_raw_document(&ins.target_id).await?.unwrap();
+        assert_eq!(
+            doc.get_document("metadata")?.get_document(&ins.work_name)?,
+            &doc! { "a": { "x": 1 } }
+        );
+        // nothing to backfill
+        assert_eq!(ins.conn.backfill_metadata().await?, 0);
+
+        // the claim keeps the metadata, into which the next one is merged.
+        let running = WorkRecord {
+            status: WorkStatus::Running,
+            metadata: Metadata::new(),
+            lease: Some(Lease::new("alice", std::time::Duration::from_secs(60))),
+            ..work_record.clone()
+        };
+        assert_matches!(
+            ins.conn.claim_work(&ins.target_id, &running).await,
+            Ok(true)
+        );
+        let wf = ins.conn.get_or_default(&ins.target_id).await?;
+        let w = wf.works.get(&ins.work_name).unwrap();
+        assert_eq!(w.status, WorkStatus::Running);
+        assert_eq!(w.metadata, doc! { "a": { "x": 1 } });
+        let merged = WorkRecord {
+            metadata: doc! { "a": { "y": 2 } },
+            attempts: 1,
+            ..work_record
+        };
+        ins.conn
+            .update_work_record(&ins.target_id, &merged, true)
+            .await?;
+        let doc = ins.conn.get_raw_document(&ins.target_id).await?.unwrap();
+        let expected = doc! { "a": { "x": 1, "y": 2 } };
+        assert_eq!(
+            doc.get_document("metadata")?.get_document(&ins.work_name)?,
+            &expected
+        );
+        let wf = ins.conn.get_or_default(&ins.target_id).await?;
+        assert_eq!(wf.works.get(&ins.work_name).unwrap().metadata, expected);
+        // the history has the metadata of the attempt.
+        let history = ins.conn.get_history(&ins.target_id, None).await?;
+        assert_eq!(history[1].metadata, doc! { "a": { "y": 2 } });
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_metadata() {
+        let mut base = doc! { "a": { "x": 1, "y": { "z": 1 } }, "b": 1, "c": { "d": 1 } };
+        merge_metadata(
+            &mut base,
+            doc! { "a": { "y": { "w": 2 } }, "b": { "e": 2 }, "c": 2, "f": 2 },
+        );
+        assert_eq!(
+            base,
+            doc! { "a": { "x": 1, "y": { "z": 1, "w": 2 } }, "b": { "e": 2 }, "c": 2, "f": 2 }
+        );
+    }
+
+    #[test]
+    fn test_metadata_paths() {
+        let paths = metadata_paths(
+            "metadata.w",
+            &doc! { "a": { "y": { "w": 2 }, "e": {} }, "b": [1, 2], "c": null },
+        )
+        .unwrap();
+        assert_eq!(
+            paths,
+            doc! { "metadata.w.a.y.w": 2, "metadata.w.b": [1, 2], "metadata.w.c": null }
+        );
+        for k in ["$set", "a.b", ""].iter() {
+            assert!(
+                metadata_paths("m", &doc! { "x": { *k: 1 } }).is_err(),
+                "{}",
+                k
+            );
+        }
+    }
+
+    #[test]
+    fn test_check_fields() {
+        assert!(check_fields(&doc! { "language": "ja", "score": 1 }).is_ok());
+        for k in ["id", "works", "history", "metadata", "$set", "a.b", ""].iter() {
+            assert!(check_fields(&doc! { *k: 1 }).is_err(), "{}", k);
+        }
+    }
+
+    #[test]
+    fn test_artifact_from_name() {
+        let a = bson::from_bson::<Artifact>(bson::Bson::String("a.wav".to_string()));
+        assert_matches!(a, Ok(Artifact { ref name, size: 0, ref sha256, .. }) if name == "a.wav" && sha256.is_empty());
+
+        let entry = Artifact {
+            name: "b.wav".to_string(),
+            size: 3,
+            sha256: "abc".to_string(),
+            content_type: "audio/wav".to_string(),
+            uploaded: bson::DateTime::now(),
+        };
+        let b = bson::to_bson(&entry).unwrap();
+        assert_eq!(bson::from_bson::<Artifact>(b).unwrap(), entry);
+    }
 }
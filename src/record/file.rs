@@ -0,0 +1,479 @@
+use super::{
+    is_claimable, is_ready, metadata_paths, History, Lease, RecordStore, WorkRecord, WorkRecordMap,
+    WorkflowRecord, HISTORY_MAX,
+};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::redact::redact;
+use async_std::path::{Path, PathBuf};
+use async_trait::async_trait;
+use mongodb::bson::{self, doc, Bson, Document};
+use std::convert::TryFrom;
+
+#[derive(Debug, Clone)]
+pub struct Connector {
+    dir: String,
+}
+impl Connector {
+    pub fn new(dir: &str) -> Self {
+        Self {
+            dir: dir.to_string(),
+        }
+    }
+    pub async fn connect(&self) -> Result<Connect> {
+        Connect::new(&self.dir).await
+    }
+}
+
+/// Each workflow record is stored in `<dir>/<target_id>.json` as relaxed extended JSON.
+/// Updates are serialized by flock(2) on `<dir>/.lock`, so that they are atomic among the processes on the host.
+pub struct Connect {
+    dir: PathBuf,
+}
+
+struct Lock {
+    _file: std::fs::File,
+}
+impl Lock {
+    async fn acquire(path: PathBuf) -> Result<Self> {
+        let file = async_std::task::spawn_blocking(move || {
+            use std::os::unix::io::AsRawFd;
+            let file = std::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(&path)?;
+            match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+                0 => Ok(file),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        })
+        .await
+        .known_error("fail to lock", false)?;
+        // the lock is released when the file is closed.
+        Ok(Self { _file: file })
+    }
+}
+
+/// escape characters which are not safe as a file name.
+fn encode_id(target_id: &str) -> String {
+    target_id
+        .bytes()
+        .map(|b| match b {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn to_json(doc: &Document) -> Result<String> {
+    let value = Bson::Document(doc.clone()).into_relaxed_extjson();
+    let s = serde_json::to_string_pretty(&value).known_error("fail to serialize to json", true)?;
+    Ok(s)
+}
+
+fn from_json(s: &str) -> Result<Document> {
+    let value: serde_json::Value = serde_json::from_str(s).known_error("malformed json", true)?;
+    match Bson::try_from(value).known_error("malformed extended json", true)? {
+        Bson::Document(doc) => Ok(doc),
+        _ => KnownErrors::normal("malformed record", true),
+    }
+}
+
+fn set_work(doc: &mut Document, work_record: &WorkRecord) -> Result<Document> {
+    let work_record_doc =
+        bson::to_document(&work_record).known_error("fail to serialize WorkRecord", true)?;
+    match doc.get_document_mut("works") {
+        Ok(works) => {
+            works.insert(&work_record.name, work_record_doc.clone());
+        }
+        Err(_) => {
+            let mut works = Document::new();
+            works.insert(&work_record.name, work_record_doc.clone());
+            doc.insert("works", works);
+        }
+    };
+    Ok(work_record_doc)
+}
+
+/// set the top-level `metadata.<name>`.
+fn set_metadata(doc: &mut Document, name: &str, metadata: &Document) {
+    match doc.get_document_mut("metadata") {
+        Ok(m) => {
+            m.insert(name, metadata.clone());
+        }
+        Err(_) => {
+            doc.insert("metadata", doc! { name: metadata.clone() });
+        }
+    };
+}
+
+fn to_workflow_record(doc: &Document) -> Result<WorkflowRecord> {
+    let workflow_record = bson::from_document::<WorkflowRecord>(doc.clone())
+        .known_error("malformed WorkflowRecord", true)?;
+    Ok(workflow_record)
+}
+
+impl Connect {
+    pub async fn new(dir: &str) -> Result<Self> {
+        async_std::fs::create_dir_all(dir)
+            .await
+            .known_error(&format!("fail to mkdir: {}", dir), true)?;
+        Ok(Self {
+            dir: PathBuf::from(dir),
+        })
+    }
+    fn path(&self, target_id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", encode_id(target_id)))
+    }
+    async fn lock(&self) -> Result<Lock> {
+        Lock::acquire(self.dir.join(".lock")).await
+    }
+    async fn read_path(path: &Path) -> Result<Option<Document>> {
+        if !path.exists().await {
+            return Ok(None);
+        }
+        let s = async_std::fs::read_to_string(path)
+            .await
+            .known_error(&format!("fail to read: {}", path.display()), false)?;
+        Ok(Some(from_json(&s)?))
+    }
+    async fn read(&self, target_id: &str) -> Result<Option<Document>> {
+        Self::read_path(&self.path(target_id)).await
+    }
+    /// write to the temporary file and rename it, so that readers never see a partial file.
+    async fn write(&self, target_id: &str, doc: &Document) -> Result<()> {
+        let path = self.path(target_id);
+        let tmp = self.dir.join(format!(".{}.tmp", encode_id(target_id)));
+        async_std::fs::write(&tmp, to_json(doc)?)
+            .await
+            .known_error(&format!("fail to write: {}", tmp.display()), false)?;
+        async_std::fs::rename(&tmp, &path)
+            .await
+            .known_error(&format!("fail to rename: {}", path.display()), false)?;
+        Ok(())
+    }
+    async fn list_paths(&self) -> Result<Vec<PathBuf>> {
+        use async_std::stream::StreamExt;
+        let mut entries = async_std::fs::read_dir(&self.dir)
+            .await
+            .known_error(&format!("fail to read dir: {}", self.dir.display()), false)?;
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let path = entry.known_error("fail to read dir entry", false)?.path();
+            let is_record = path
+                .file_name()
+                .and_then(|s| s.to_str())
+                .is_some_and(|s| !s.starts_with('.') && s.ends_with(".json"));
+            if is_record {
+                paths.push(path);
+            }
+        }
+        Ok(paths)
+    }
+}
+
+#[async_trait]
+impl RecordStore for Connect {
+    async fn delete_all(&mut self) -> Result<()> {
+        let _lock = self.lock().await?;
+        for path in self.list_paths().await?.iter() {
+            async_std::fs::remove_file(path)
+                .await
+                .known_error(&format!("fail to remove: {}", path.display()), false)?;
+        }
+        Ok(())
+    }
+    #[cfg(test)]
+    async fn get_raw_document(&mut self, target_id: &str) -> Result<Option<Document>> {
+        self.read(target_id).await
+    }
+    async fn get_or_default(&mut self, target_id: &str) -> Result<WorkflowRecord> {
+        let _lock = self.lock().await?;
+        let mut doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => {
+                let workflow_record = WorkflowRecord {
+                    id: target_id.to_string(),
+                    works: WorkRecordMap::new(),
+                };
+                let doc = bson::to_document(&workflow_record)?;
+                self.write(target_id, &doc).await?;
+                doc
+            }
+        };
+        doc.remove("history");
+        println!("load WorkflowResult: {}", redact(&doc.to_string()));
+        to_workflow_record(&doc)
+    }
+    async fn find_ready(
+        &mut self,
+        work_name: &str,
+        depends: &[crate::envvar::Depend],
+    ) -> Result<Vec<String>> {
+        let now = bson::DateTime::now();
+        let mut ids = Vec::new();
+        for path in self.list_paths().await?.iter() {
+            if let Some(doc) = Self::read_path(path).await? {
+                let workflow_record = to_workflow_record(&doc)?;
+                if is_ready(&workflow_record, work_name, depends, now) {
+                    ids.push(workflow_record.id);
+                }
+            }
+        }
+        ids.sort();
+        Ok(ids)
+    }
+    async fn claim_work(&mut self, target_id: &str, work_record: &WorkRecord) -> Result<bool> {
+        let owner = match work_record.lease {
+            Some(ref lease) => &lease.owner,
+            None => return KnownErrors::normal("lease is not given", true),
+        };
+        let _lock = self.lock().await?;
+        let mut doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => return Ok(false),
+        };
+        let workflow_record = to_workflow_record(&doc)?;
+        let work = workflow_record.works.get(&work_record.name);
+        if !is_claimable(work, owner, bson::DateTime::now()) {
+            return Ok(false);
+        }
+        let mut work_record = work_record.clone();
+        work_record.metadata = work.map(|w| w.metadata.clone()).unwrap_or_default();
+        set_work(&mut doc, &work_record)?;
+        self.write(target_id, &doc).await?;
+        Ok(true)
+    }
+    async fn renew_lease(
+        &mut self,
+        target_id: &str,
+        work_name: &str,
+        lease: &Lease,
+    ) -> Result<bool> {
+        let _lock = self.lock().await?;
+        let mut doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => return Ok(false),
+        };
+        let workflow_record = to_workflow_record(&doc)?;
+        let mut work = match workflow_record.works.get(work_name) {
+            Some(w) if w.status == super::WorkStatus::Running => w.clone(),
+            _ => return Ok(false),
+        };
+        match work.lease {
+            Some(ref mut l) if l.owner == lease.owner => l.expires = lease.expires,
+            _ => return Ok(false),
+        };
+        set_work(&mut doc, &work)?;
+        self.write(target_id, &doc).await?;
+        Ok(true)
+    }
+    async fn update_work_record(
+        &mut self,
+        target_id: &str,
+        work_record: &WorkRecord,
+        merge_metadata: bool,
+    ) -> Result<()> {
+        let _lock = self.lock().await?;
+        // same as update_one of MongoDB, nothing is done if the document does not exist.
+        let mut doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => return Ok(()),
+        };
+        if has_attempt(&doc, work_record) {
+            return Ok(());
+        }
+        let mut work_record = work_record.clone();
+        work_record.attempts = doc
+            .get_document("works")
+            .and_then(|works| works.get_document(&work_record.name))
+            .ok()
+            .and_then(|w| w.get("attempts"))
+            .and_then(|b| bson::from_bson::<u32>(b.clone()).ok())
+            .unwrap_or(0)
+            + 1;
+        let work_record_doc =
+            bson::to_document(&work_record).known_error("fail to serialize WorkRecord", true)?;
+        if merge_metadata {
+            // the keys are checked as MongoDB does.
+            metadata_paths("metadata", &work_record.metadata)?;
+            let mut merged = doc
+                .get_document("metadata")
+                .and_then(|m| m.get_document(&work_record.name))
+                .or_else(|_| {
+                    doc.get_document("works")
+                        .and_then(|works| works.get_document(&work_record.name))
+                        .and_then(|w| w.get_document("metadata"))
+                })
+                .cloned()
+                .unwrap_or_default();
+            super::merge_metadata(&mut merged, work_record.metadata.clone());
+            work_record.metadata = merged;
+        }
+        set_work(&mut doc, &work_record)?;
+        set_metadata(&mut doc, &work_record.name, &work_record.metadata);
+        push_history(&mut doc, work_record_doc, HISTORY_MAX);
+        self.write(target_id, &doc).await
+    }
+    async fn get_history(&mut self, target_id: &str, work_name: Option<&str>) -> Result<History> {
+        let doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => return KnownErrors::normal(&format!("{} is not found", target_id), true),
+        };
+        let history = match doc.get_array("history") {
+            Err(_) => History::new(),
+            Ok(arr) => arr
+                .iter()
+                .map(|b| bson::from_bson::<WorkRecord>(b.clone()))
+                .collect::<std::result::Result<History, _>>()
+                .known_error("malformed history", true)?,
+        };
+        Ok(history
+            .into_iter()
+            .filter(|w| match work_name {
+                Some(name) => w.name == name,
+                None => true,
+            })
+            .collect())
+    }
+    async fn set_fields(&mut self, target_id: &str, fields: &Document) -> Result<()> {
+        super::check_fields(fields)?;
+        let _lock = self.lock().await?;
+        // same as update_one of MongoDB, nothing is done if the document does not exist.
+        let mut doc = match self.read(target_id).await? {
+            Some(doc) => doc,
+            None => return Ok(()),
+        };
+        for (k, v) in fields.iter() {
+            doc.insert(k, v.clone());
+        }
+        self.write(target_id, &doc).await
+    }
+    async fn backfill_metadata(&mut self) -> Result<u64> {
+        let _lock = self.lock().await?;
+        let mut n = 0;
+        for path in self.list_paths().await?.iter() {
+            let mut doc = match Self::read_path(path).await? {
+                Some(doc) => doc,
+                None => continue,
+            };
+            let mut metadata: Document = match doc.get_document("works") {
+                Ok(works) => works
+                    .iter()
+                    .map(|(name, w)| {
+                        let m = match w {
+                            Bson::Document(w) => w.get_document("metadata").ok().cloned(),
+                            _ => None,
+                        };
+                        (name.clone(), Bson::Document(m.unwrap_or_default()))
+                    })
+                    .collect(),
+                Err(_) => continue,
+            };
+            // only the missing ones are set, as MongoDB does.
+            match doc.get("metadata") {
+                None => (),
+                Some(Bson::Document(m)) => metadata.extend(m.clone()),
+                Some(_) => continue,
+            }
+            if doc.get_document("metadata").ok() == Some(&metadata) {
+                continue;
+            }
+            doc.insert("metadata", metadata);
+            let target_id = to_workflow_record(&doc)?.id;
+            self.write(&target_id, &doc).await?;
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+/// same as `$push` with `$slice: -max` of MongoDB.
+/// whether the history has the attempt of `work_record`, which is recorded already.
+fn has_attempt(doc: &Document, work_record: &WorkRecord) -> bool {
+    doc.get_array("history").is_ok_and(|history| {
+        history
+            .iter()
+            .filter_map(|b| bson::from_bson::<WorkRecord>(b.clone()).ok())
+            .any(|w| {
+                w.name == work_record.name
+                    && w.attempts == work_record.attempts + 1
+                    && w.updated == work_record.updated
+            })
+    })
+}
+
+fn push_history(doc: &mut Document, work_record_doc: Document, max: usize) {
+    match doc.get_array_mut("history") {
+        Ok(history) => {
+            history.push(Bson::Document(work_record_doc));
+            let over = history.len().saturating_sub(max);
+            history.drain(..over);
+        }
+        Err(_) => {
+            doc.insert("history", vec![Bson::Document(work_record_doc)]);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn test_backfill_metadata() -> Result<()> {
+        let dir = std::env::temp_dir().join("loadwork-test-backfill");
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut conn = Connect::new(dir.to_str().unwrap()).await?;
+        // written by the old versions
+        let legacy = doc! {
+            "id": "t1",
+            "works": {
+                "a": { "name": "a", "version": "1", "status": "Succeeded", "error": null,
+                       "updated": bson::DateTime::now(), "artifacts": [], "metadata": { "x": 1 } },
+            },
+        };
+        conn.write("t1", &legacy).await?;
+        conn.get_or_default("t2").await?;
+        // the metadata of the running work is kept, which the old versions cleared in works by the claim.
+        let running = doc! {
+            "id": "t3",
+            "works": {
+                "a": { "name": "a", "version": "1", "status": "Running", "error": null,
+                       "updated": bson::DateTime::now(), "artifacts": [], "metadata": {} },
+                "b": { "name": "b", "version": "1", "status": "Succeeded", "error": null,
+                       "updated": bson::DateTime::now(), "artifacts": [], "metadata": { "y": 2 } },
+            },
+            "metadata": { "a": { "x": 1 } },
+        };
+        conn.write("t3", &running).await?;
+
+        assert_eq!(conn.backfill_metadata().await?, 3);
+        let doc = conn.read("t1").await?.unwrap();
+        assert_eq!(doc.get_document("metadata")?, &doc! { "a": { "x": 1 } });
+        let doc = conn.read("t3").await?.unwrap();
+        assert_eq!(
+            doc.get_document("metadata")?,
+            &doc! { "a": { "x": 1 }, "b": { "y": 2 } }
+        );
+        let doc = conn.read("t2").await?.unwrap();
+        assert_eq!(doc.get_document("metadata")?, &Document::new());
+        assert_eq!(conn.backfill_metadata().await?, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_push_history() {
+        let mut doc = doc! { "id": "t1" };
+        for i in 0..3 {
+            push_history(&mut doc, doc! { "attempts": i }, 2);
+        }
+        assert_eq!(
+            doc.get_array("history").unwrap(),
+            &vec![
+                Bson::Document(doc! { "attempts": 1 }),
+                Bson::Document(doc! { "attempts": 2 })
+            ]
+        );
+    }
+}
@@ -0,0 +1,504 @@
+use super::{
+    metadata_paths, History, Lease, RecordStore, WorkRecord, WorkRecordMap, WorkStatus,
+    WorkflowRecord, HISTORY_MAX,
+};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::redact::redact;
+use crate::retry::{Policy, Stage};
+use async_trait::async_trait;
+use mongodb::{
+    bson::{self, doc, Document},
+    options::{FindOneOptions, FindOptions, UpdateModifications, UpdateOptions},
+};
+
+fn db_key(target_id: &str) -> Document {
+    doc! { "id": target_id.to_string() }
+}
+
+fn status_bson(status: WorkStatus) -> Result<bson::Bson> {
+    let b = bson::to_bson(&status).known_error("fail to serialize WorkStatus", true)?;
+    Ok(b)
+}
+
+/// build a filter which matches the documents where all `depends` are succeeded at the required version
+/// and the work `work_name` is not recorded yet, failed retryably or its lease is expired, as `is_ready`.
+fn ready_filter(work_name: &str, depends: &[crate::envvar::Depend]) -> Result<Document> {
+    let mut filter = Document::new();
+    for dep in depends.iter() {
+        filter.insert(
+            format!("works.{}.status", dep.work_name),
+            status_bson(WorkStatus::Succeeded)?,
+        );
+        filter.insert(
+            format!("works.{}.version", dep.work_name),
+            dep.work_version.clone(),
+        );
+    }
+    filter.insert(
+        "$or",
+        vec![
+            doc! { format!("works.{}", work_name): { "$exists": false } },
+            doc! { format!("works.{}.status", work_name): status_bson(WorkStatus::FailRetryable)? },
+            doc! {
+                format!("works.{}.status", work_name): status_bson(WorkStatus::Running)?,
+                format!("works.{}.lease.expires", work_name): { "$lt": bson::DateTime::now() },
+            },
+        ],
+    );
+    Ok(filter)
+}
+
+/// build a filter which matches the document where the work `work_name` can be claimed by `owner`.
+/// that is, no one is running it, the lease of the runner is expired, or it is `owner` itself.
+fn claimable_filter(target_id: &str, work_name: &str, owner: &str) -> Result<Document> {
+    let mut filter = db_key(target_id);
+    filter.insert(
+        "$or",
+        vec![
+            doc! { format!("works.{}", work_name): { "$exists": false } },
+            doc! { format!("works.{}.status", work_name): { "$ne": status_bson(WorkStatus::Running)? } },
+            doc! { format!("works.{}.lease.expires", work_name): { "$lt": bson::DateTime::now() } },
+            doc! { format!("works.{}.lease.owner", work_name): owner.to_string() },
+        ],
+    );
+    Ok(filter)
+}
+
+#[derive(Debug, Clone)]
+pub struct Connector {
+    url: String,
+    database: String,
+    collection: String,
+    retry: Policy,
+}
+impl Connector {
+    /// `LW_MONGODB_URI` is used as the connection string if it is set,
+    /// otherwise it is composed of `LW_MONGODB_HOST`, `LW_MONGODB_USERNAME` and so on.
+    pub fn new_from_env() -> Result<Self> {
+        use crate::envvar;
+        let collection = envvar::mongodb_collection()?;
+        if let Some(uri) = envvar::mongodb_uri_opt()? {
+            let parts = envvar::mongodb_uri_parts_set();
+            if !parts.is_empty() {
+                return KnownErrors::normal(
+                    &format!("LW_MONGODB_URI cannot be used with {}", parts.join(", ")),
+                    true,
+                );
+            }
+            let database = resolve_database(&uri, envvar::mongodb_database_opt())?;
+            let mut c = Connector::new_with_uri(uri, database, collection);
+            c.retry = Policy::new_from_env(Stage::Record)?;
+            return Ok(c);
+        }
+        let host = envvar::mongodb_host()?;
+        let port = envvar::mongodb_port();
+        let user = envvar::mongodb_username()?;
+        let pass = envvar::mongodb_password()?;
+        let urlbase = format!("mongodb://{}:{}@{}:{}", user, pass, host, port);
+        let options = envvar::mongodb_options();
+        let database = envvar::mongodb_database()?;
+        //println!("mongodb: urlbase={}, db={}, coll={}", urlbase, database, collection);
+        let mut c = Connector::new(urlbase, options, database, collection);
+        c.retry = Policy::new_from_env(Stage::Record)?;
+        Ok(c)
+    }
+    pub fn new(urlbase: String, options: String, database: String, collection: String) -> Self {
+        Self {
+            url: format!("{}/{}?{}", urlbase, database, options),
+            database: database,
+            collection: collection,
+            retry: Policy::new_default(Stage::Record),
+        }
+    }
+    pub fn new_with_uri(uri: String, database: String, collection: String) -> Self {
+        Self {
+            url: uri,
+            database,
+            collection,
+            retry: Policy::new_default(Stage::Record),
+        }
+    }
+    pub async fn connect(&self) -> Result<Connect> {
+        Connect::new(
+            &self.url,
+            &self.database,
+            &self.collection,
+            self.retry.clone(),
+        )
+        .await
+    }
+    /// the number of the retried operations.
+    pub fn retried(&self) -> u32 {
+        self.retry.retried()
+    }
+}
+
+/// returns the default database in the path of the connection string,
+/// `mongodb[+srv]://[user:pass@]host1[:port1][,host2...]/[database][?options]`.
+fn uri_database(uri: &str) -> Result<Option<String>> {
+    let rest = match uri
+        .strip_prefix("mongodb://")
+        .or_else(|| uri.strip_prefix("mongodb+srv://"))
+    {
+        Some(rest) => rest,
+        None => return KnownErrors::normal("invalid LW_MONGODB_URI: unknown scheme", true),
+    };
+    let rest = rest.split('?').next().unwrap_or("");
+    let database = rest.split_once('/').map(|(_, db)| db).unwrap_or("");
+    match database.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(database.to_string())),
+    }
+}
+
+/// the database is given by `LW_MONGODB_DATABASE` or the connection string, and they must be same if both are given.
+fn resolve_database(uri: &str, database_opt: Option<String>) -> Result<String> {
+    match (database_opt, uri_database(uri)?) {
+        (Some(a), Some(b)) if a != b => KnownErrors::normal(
+            &format!(
+                "LW_MONGODB_DATABASE is {} but LW_MONGODB_URI specifies {}",
+                a, b
+            ),
+            true,
+        ),
+        (Some(a), _) => Ok(a),
+        (None, Some(b)) => Ok(b),
+        (None, None) => KnownErrors::required("LW_MONGODB_DATABASE"),
+    }
+}
+
+pub struct Connect {
+    coll: mongodb::Collection<Document>,
+    retry: Policy,
+}
+
+impl Connect {
+    #[allow(dead_code)]
+    pub async fn new_from_env() -> Result<Self> {
+        let c = Connector::new_from_env()?;
+        c.connect().await
+    }
+    pub async fn new(url: &str, database: &str, collection: &str, retry: Policy) -> Result<Self> {
+        //println!("mongodb: connectiong to '{}'", url);
+        let mongodb_client = mongodb::Client::with_uri_str(&url).await.known_error(
+            &format!("fail to connect: {}", crate::redact::strip_userinfo(url)),
+            true,
+        )?;
+        let mongodb_coll = mongodb_client.database(database).collection(collection);
+        Ok(Self {
+            coll: mongodb_coll,
+            retry,
+        })
+    }
+}
+
+#[async_trait]
+impl RecordStore for Connect {
+    async fn delete_all(&mut self) -> Result<()> {
+        let _ = self.coll.drop(None).await?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    async fn get_raw_document(&mut self, target_id: &str) -> Result<Option<Document>> {
+        let key = db_key(target_id);
+        let opt_doc = self
+            .coll
+            .find_one(key.clone(), None)
+            .await
+            .known_error("fail to find", true)?;
+        Ok(opt_doc)
+    }
+    async fn get_or_default(&mut self, target_id: &str) -> Result<WorkflowRecord> {
+        let key = db_key(target_id);
+        let workflow_record = WorkflowRecord {
+            id: target_id.to_string(),
+            works: WorkRecordMap::new(),
+        };
+        let doc = bson::to_document(&workflow_record)?;
+        let coll = &self.coll;
+        self.retry
+            .run("upsert", || async {
+                coll.update_one(
+                    key.clone(),
+                    doc! { "$setOnInsert": doc.clone() },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await
+                .known_error("fail to upsert", false)?;
+                Ok(())
+            })
+            .await?;
+
+        let opt_doc = self
+            .retry
+            .run("find", || async {
+                Ok(coll
+                    .find_one(
+                        key.clone(),
+                        FindOneOptions::builder()
+                            .projection(doc! { "history": 0 })
+                            .build(),
+                    )
+                    .await
+                    .known_error("fail to find", false)?)
+            })
+            .await?;
+        let doc = match opt_doc {
+            Some(doc) => Ok(doc),
+            None => KnownErrors::normal::<Document>("no document is found", true),
+        }?;
+        println!("load WorkflowResult: {}", redact(&doc.to_string()));
+        let workflow_record = bson::from_document::<WorkflowRecord>(doc)?;
+        Ok(workflow_record)
+    }
+    async fn find_ready(
+        &mut self,
+        work_name: &str,
+        depends: &[crate::envvar::Depend],
+    ) -> Result<Vec<String>> {
+        use futures::stream::TryStreamExt;
+        let filter = ready_filter(work_name, depends)?;
+        let options = FindOptions::builder()
+            .projection(doc! { "id": 1 })
+            .sort(doc! { "id": 1 })
+            .build();
+        let docs: Vec<Document> = self
+            .coll
+            .find(filter, options)
+            .await
+            .known_error("fail to find", false)?
+            .try_collect()
+            .await
+            .known_error("fail to read cursor", false)?;
+        let ids = docs
+            .iter()
+            .map(|doc| doc.get_str("id").map(|s| s.to_string()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .known_error("malformed document", true)?;
+        Ok(ids)
+    }
+    async fn claim_work(&mut self, target_id: &str, work_record: &WorkRecord) -> Result<bool> {
+        let owner = match work_record.lease {
+            Some(ref lease) => &lease.owner,
+            None => return KnownErrors::normal("lease is not given", true),
+        };
+        let filter = claimable_filter(target_id, &work_record.name, owner)?;
+        let mut work_record_doc =
+            bson::to_document(&work_record).known_error("fail to serialize WorkRecord", true)?;
+        work_record_doc.remove("metadata");
+        // the recorded metadata is kept by the server.
+        let prefix = format!("works.{}", &work_record.name);
+        let pipeline = vec![doc! { "$set": { &prefix: { "$mergeObjects": [
+            { "$literal": work_record_doc },
+            { "metadata": { "$ifNull": [format!("${}.metadata", prefix), {}] } },
+        ] } } }];
+        let r = self
+            .coll
+            .update_one(filter, UpdateModifications::Pipeline(pipeline), None)
+            .await
+            .known_error("fail to claim work", false)?;
+        Ok(r.matched_count == 1)
+    }
+    async fn renew_lease(
+        &mut self,
+        target_id: &str,
+        work_name: &str,
+        lease: &Lease,
+    ) -> Result<bool> {
+        let mut filter = db_key(target_id);
+        filter.insert(
+            format!("works.{}.status", work_name),
+            status_bson(WorkStatus::Running)?,
+        );
+        filter.insert(
+            format!("works.{}.lease.owner", work_name),
+            lease.owner.clone(),
+        );
+        let r = self
+            .coll
+            .update_one(
+                filter,
+                doc! { "$set": { &format!("works.{}.lease.expires", work_name) : lease.expires } },
+                None,
+            )
+            .await
+            .known_error("fail to renew lease", false)?;
+        Ok(r.matched_count == 1)
+    }
+    async fn update_work_record(
+        &mut self,
+        target_id: &str,
+        work_record: &WorkRecord,
+        merge_metadata: bool,
+    ) -> Result<()> {
+        let key = db_key(target_id);
+
+        // the history gets `work_record.attempts + 1` computed on this clone, while the stored one is incremented by `$inc`.
+        // they agree only when the caller passes the attempts of the claimed work, as `run` does.
+        let mut history_record = work_record.clone();
+        history_record.attempts += 1;
+        let history_doc =
+            bson::to_document(&history_record).known_error("fail to serialize WorkRecord", true)?;
+        //println!("save WorkRecord: {}", history_doc); caution this may be too long
+
+        // each field is set except attempts, which is incremented in the same update.
+        let prefix = format!("works.{}", &work_record.name);
+        let mut set = Document::new();
+        for (k, v) in history_doc.iter().filter(|(k, _)| *k != "attempts") {
+            if merge_metadata && *k == "metadata" {
+                continue;
+            }
+            set.insert(format!("{}.{}", prefix, k), v.clone());
+        }
+        let metadata_prefix = format!("metadata.{}", &work_record.name);
+        match merge_metadata {
+            // merged by the server, so that the concurrent updates of the other keys are not lost.
+            true => {
+                set.extend(metadata_paths(
+                    &format!("{}.metadata", prefix),
+                    &work_record.metadata,
+                )?);
+                set.extend(metadata_paths(&metadata_prefix, &work_record.metadata)?);
+            }
+            false => {
+                set.insert(metadata_prefix, work_record.metadata.clone());
+            }
+        }
+        let mut update = doc! {
+            "$set": set,
+            "$inc": { &format!("{}.attempts", prefix): 1 },
+            "$push": { "history": { "$each": [history_doc.clone()], "$slice": -(HISTORY_MAX as i64) } },
+        };
+        if !history_doc.contains_key("retries") {
+            update.insert("$unset", doc! { &format!("{}.retries", prefix): "" });
+        }
+
+        // the update is skipped if the history has the attempt, so that it is retried
+        // without incrementing attempts twice after the response of an applied update is lost.
+        let mut filter = key;
+        filter.insert(
+            "history",
+            doc! { "$not": { "$elemMatch": {
+                "name": &work_record.name,
+                "attempts": history_doc.get("attempts"),
+                "updated": history_doc.get("updated"),
+            } } },
+        );
+        let coll = &self.coll;
+        self.retry
+            .run("update", || async {
+                coll.update_one(filter.clone(), update.clone(), None)
+                    .await
+                    .known_error("fail to update work record", false)?;
+                Ok(())
+            })
+            .await
+    }
+    async fn get_history(&mut self, target_id: &str, work_name: Option<&str>) -> Result<History> {
+        let key = db_key(target_id);
+        let opt_doc = self
+            .coll
+            .find_one(
+                key,
+                FindOneOptions::builder()
+                    .projection(doc! { "history": 1 })
+                    .build(),
+            )
+            .await
+            .known_error("fail to find", false)?;
+        let doc = match opt_doc {
+            Some(doc) => doc,
+            None => return KnownErrors::normal(&format!("{} is not found", target_id), true),
+        };
+        let history = match doc.get_array("history") {
+            Err(_) => History::new(),
+            Ok(arr) => arr
+                .iter()
+                .map(|b| bson::from_bson::<WorkRecord>(b.clone()))
+                .collect::<std::result::Result<History, _>>()
+                .known_error("malformed history", true)?,
+        };
+        Ok(history
+            .into_iter()
+            .filter(|w| match work_name {
+                Some(name) => w.name == name,
+                None => true,
+            })
+            .collect())
+    }
+    async fn set_fields(&mut self, target_id: &str, fields: &Document) -> Result<()> {
+        super::check_fields(fields)?;
+        let _ = self
+            .coll
+            .update_one(db_key(target_id), doc! { "$set": fields.clone() }, None)
+            .await
+            .known_error("fail to set fields", false)?;
+        Ok(())
+    }
+    async fn backfill_metadata(&mut self) -> Result<u64> {
+        // only the missing `metadata.<name>` are set by the server, since the existing ones are newer than
+        // `works.<name>.metadata` which the old versions cleared while the work is running.
+        let pipeline = vec![doc! { "$set": { "metadata": { "$mergeObjects": [
+            { "$arrayToObject": { "$map": {
+                "input": { "$objectToArray": "$works" },
+                "in": { "k": "$$this.k", "v": { "$ifNull": ["$$this.v.metadata", {}] } },
+            } } },
+            { "$ifNull": ["$metadata", {}] },
+        ] } } }];
+        let r = self
+            .coll
+            .update_many(
+                doc! {
+                    "works": { "$type": "object" },
+                    "$or": [{ "metadata": { "$exists": false } }, { "metadata": { "$type": "object" } }],
+                },
+                UpdateModifications::Pipeline(pipeline),
+                None,
+            )
+            .await
+            .known_error("fail to backfill metadata", false)?;
+        Ok(r.modified_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_matches::assert_matches;
+
+    #[test]
+    fn test_uri_database() {
+        let db = |uri: &str| uri_database(uri).unwrap();
+        assert_eq!(db("mongodb://u:p@host:27017"), None);
+        assert_eq!(db("mongodb://host/"), None);
+        assert_eq!(db("mongodb://host/?authSource=admin"), None);
+        assert_eq!(
+            db("mongodb://h1:1,h2:2/lw?replicaSet=rs0"),
+            Some("lw".to_string())
+        );
+        assert_eq!(
+            db("mongodb+srv://u:p@cluster.example.com/lw"),
+            Some("lw".to_string())
+        );
+        assert_matches!(uri_database("http://host/lw"), Err(_));
+    }
+
+    #[test]
+    fn test_resolve_database() {
+        let uri = "mongodb+srv://cluster.example.com/lw?tls=true";
+        assert_eq!(resolve_database(uri, None).unwrap(), "lw");
+        assert_eq!(resolve_database(uri, Some("lw".to_string())).unwrap(), "lw");
+        let r = resolve_database(uri, Some("other".to_string()));
+        assert_eq!(
+            r.err().unwrap().downcast_ref::<KnownErrors>(),
+            Some(&KnownErrors::Normal(
+                "LW_MONGODB_DATABASE is other but LW_MONGODB_URI specifies lw".to_string(),
+                true
+            ))
+        );
+        let uri = "mongodb://host:27017/";
+        assert_eq!(resolve_database(uri, Some("lw".to_string())).unwrap(), "lw");
+        assert_matches!(resolve_database(uri, None), Err(_));
+    }
+}
@@ -0,0 +1,256 @@
+use crate::error::{KnownErrors, Result};
+use crate::redact::redact;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// the stages which are retried by their own policies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// reading the workflow record from MongoDB.
+    Record,
+    /// uploading and downloading the artifacts to S3.
+    Artifact,
+    /// executing the program.
+    Program,
+}
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Record => "record",
+            Stage::Artifact => "artifact",
+            Stage::Program => "program",
+        }
+    }
+    /// the program is not retried by default, since it may take long.
+    fn default_policy(&self) -> Policy {
+        match self {
+            Stage::Record | Stage::Artifact => Policy::new(
+                *self,
+                3,
+                Duration::from_secs(1),
+                Duration::from_secs(30),
+                0.5,
+            ),
+            Stage::Program => Policy::new(
+                *self,
+                1,
+                Duration::from_secs(10),
+                Duration::from_secs(300),
+                0.5,
+            ),
+        }
+    }
+}
+
+/// how many times and how long to wait before retrying a stage.
+/// the delay of the n-th retry is `base_delay * 2^(n-1)` up to `max_delay`,
+/// and it is randomly shortened by `jitter` at most, e.g. 0.5 for 50%.
+/// the clones share the number of the retried attempts.
+#[derive(Debug, Clone)]
+pub struct Policy {
+    pub stage: Stage,
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub jitter: f64,
+    retried: Arc<AtomicU32>,
+}
+
+impl Policy {
+    pub fn new(
+        stage: Stage,
+        max_attempts: u32,
+        base_delay: Duration,
+        max_delay: Duration,
+        jitter: f64,
+    ) -> Self {
+        Self {
+            stage,
+            max_attempts,
+            base_delay,
+            max_delay,
+            jitter,
+            retried: Arc::new(AtomicU32::new(0)),
+        }
+    }
+    pub fn new_default(stage: Stage) -> Self {
+        stage.default_policy()
+    }
+    /// `LW_RETRY_<STAGE>_{MAX_ATTEMPTS,BASE_DELAY_MS,MAX_DELAY_MS,JITTER}` override the defaults.
+    pub fn new_from_env(stage: Stage) -> Result<Self> {
+        use crate::envvar;
+        let d = stage.default_policy();
+        let ms = |name: &str, default: Duration| -> Result<Duration> {
+            Ok(envvar::retry_u64_opt(stage.name(), name)?.map_or(default, Duration::from_millis))
+        };
+        let max_attempts = match envvar::retry_u64_opt(stage.name(), "MAX_ATTEMPTS")? {
+            None => d.max_attempts,
+            Some(n) => n.max(1) as u32,
+        };
+        Ok(Self::new(
+            stage,
+            max_attempts,
+            ms("BASE_DELAY_MS", d.base_delay)?,
+            ms("MAX_DELAY_MS", d.max_delay)?,
+            envvar::retry_jitter_opt(stage.name())?.unwrap_or(d.jitter),
+        ))
+    }
+
+    /// the number of the attempts which have been retried.
+    pub fn retried(&self) -> u32 {
+        self.retried.load(Ordering::SeqCst)
+    }
+
+    /// the delay before the next attempt if the `attempt`-th attempt failed with `e` and it can be retried.
+    /// the retry is counted and printed.
+    pub fn next_delay(
+        &self,
+        what: &str,
+        attempt: u32,
+        e: &(dyn std::error::Error + Send + Sync + 'static),
+    ) -> Option<Duration> {
+        if self.max_attempts <= attempt || !is_retryable(e) {
+            return None;
+        }
+        let delay = self.delay(attempt);
+        self.retried.fetch_add(1, Ordering::SeqCst);
+        println!(
+            "{}: {} attempt {}/{} failed, retry in {} ms: {}",
+            what,
+            self.stage.name(),
+            attempt,
+            self.max_attempts,
+            delay.as_millis(),
+            redact(&e.to_string())
+        );
+        Some(delay)
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .checked_mul(1u32.checked_shl(attempt - 1).unwrap_or(u32::MAX))
+            .unwrap_or(self.max_delay)
+            .min(self.max_delay);
+        exp.mul_f64(1.0 - self.jitter.clamp(0.0, 1.0) * random())
+    }
+
+    /// call `f` until it succeeds, fails permanently, or reaches `max_attempts`.
+    pub async fn run<T, F, Fut>(&self, what: &str, mut f: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f().await {
+                Ok(t) => return Ok(t),
+                Err(e) => match self.next_delay(what, attempt, e.as_ref()) {
+                    None => return Err(e),
+                    Some(delay) => async_std::task::sleep(delay).await,
+                },
+            }
+            attempt += 1;
+        }
+    }
+}
+
+/// the errors which are not known are retried, since they are mostly of I/O.
+pub fn is_retryable(e: &(dyn std::error::Error + Send + Sync + 'static)) -> bool {
+    match e.downcast_ref::<KnownErrors>() {
+        Some(KnownErrors::Normal(_, permanent)) => !permanent,
+        Some(KnownErrors::Timeout(_, permanent)) => !permanent,
+        Some(KnownErrors::Required(_)) => false,
+        Some(KnownErrors::Cancelled(_)) => false,
+        Some(KnownErrors::Skipped(_)) => false,
+        None => true,
+    }
+}
+
+/// a number in [0, 1) for the jitter.
+fn random() -> f64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+    let mut h = RandomState::new().build_hasher();
+    h.write_u128(
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos()),
+    );
+    (h.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(max_attempts: u32) -> Policy {
+        Policy::new(
+            Stage::Artifact,
+            max_attempts,
+            Duration::from_millis(10),
+            Duration::from_millis(25),
+            0.0,
+        )
+    }
+
+    #[test]
+    fn test_delay() {
+        let p = policy(5);
+        assert_eq!(p.delay(1), Duration::from_millis(10));
+        assert_eq!(p.delay(2), Duration::from_millis(20));
+        assert_eq!(p.delay(3), Duration::from_millis(25));
+        assert_eq!(p.delay(100), Duration::from_millis(25));
+        let p = Policy { jitter: 0.5, ..p };
+        for _ in 0..10 {
+            let d = p.delay(1);
+            assert!(Duration::from_millis(5) <= d && d <= Duration::from_millis(10));
+        }
+    }
+
+    #[async_std::test]
+    async fn test_run() -> Result<()> {
+        let p = policy(3);
+        let mut n = 0;
+        let r = p
+            .run("test", || {
+                n += 1;
+                let n = n;
+                async move {
+                    match n {
+                        1 => KnownErrors::normal("transient", false),
+                        _ => Ok(n),
+                    }
+                }
+            })
+            .await;
+        assert_eq!(r.unwrap(), 2);
+        assert_eq!(p.retried(), 1);
+
+        // permanent errors are not retried
+        let mut n = 0;
+        let r: Result<()> = p
+            .run("test", || {
+                n += 1;
+                async { KnownErrors::normal("permanent", true) }
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(n, 1);
+
+        // up to max_attempts
+        let mut n = 0;
+        let r: Result<()> = p
+            .run("test", || {
+                n += 1;
+                async { Err("io".into()) }
+            })
+            .await;
+        assert!(r.is_err());
+        assert_eq!(n, 3);
+        assert_eq!(p.clone().retried(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,441 @@
+use super::{cancel, run, Config, RunOutcome};
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::redact::redact;
+use crate::spec::Spec;
+use futures::future::{FutureExt, LocalBoxFuture};
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::BTreeMap;
+
+/// what to do when a work fails.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFailure {
+    /// start no more works, and wait for the running ones.
+    Stop,
+    /// run the works which do not depend on the failed one.
+    Continue,
+}
+impl std::str::FromStr for OnFailure {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "stop" => Ok(OnFailure::Stop),
+            "continue" => Ok(OnFailure::Continue),
+            _ => Err(format!("\"stop\" or \"continue\" but {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AllConfig {
+    spec: Spec,
+    target_id: String,
+    parallel: usize,
+    on_failure: OnFailure,
+    force: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+    /// skipped by the program, or not started because a depend failed, was skipped or the policy stopped.
+    Skipped,
+}
+
+#[allow(dead_code)]
+pub async fn run_all_from_env(args: &[String]) -> Result<()> {
+    use crate::envvar;
+    cancel::install()?;
+    let mut spec_path = envvar::spec_opt();
+    let mut parallel = envvar::parallel()?;
+    let mut on_failure = envvar::on_failure()
+        .map_or(Ok(OnFailure::Stop), |s| s.parse())
+        .known_error("invalid LW_ON_FAILURE", true)?;
+    let mut force = envvar::force();
+    let mut args = args;
+    while let Some(arg) = args.first() {
+        match arg.as_str() {
+            "--force" => force = true,
+            "--spec" => {
+                let path = args
+                    .get(1)
+                    .ok_or("--spec <path>")
+                    .known_error("invalid argument", true)?;
+                spec_path = Some(path.clone());
+                args = &args[1..];
+            }
+            "--parallel" => {
+                parallel = args
+                    .get(1)
+                    .and_then(|s| s.parse::<usize>().ok())
+                    .filter(|n| 0 < *n)
+                    .ok_or("--parallel <n>")
+                    .known_error("invalid argument", true)?;
+                args = &args[1..];
+            }
+            "--on-failure" => {
+                on_failure = args
+                    .get(1)
+                    .ok_or("--on-failure stop|continue".to_string())
+                    .and_then(|s| s.parse())
+                    .known_error("invalid argument", true)?;
+                args = &args[1..];
+            }
+            _ => break,
+        }
+        args = &args[1..];
+    }
+    let target_id = match args {
+        [target_id] => target_id.clone(),
+        _ => return KnownErrors::normal("usage: run-all [options] <target_id>", true),
+    };
+    let spec_path = spec_path
+        .ok_or("LW_SPEC or --spec is required")
+        .known_error("invalid argument", true)?;
+    let config = AllConfig {
+        spec: Spec::load(&spec_path)?,
+        target_id,
+        parallel,
+        on_failure,
+        force,
+    };
+    run_all(&config).await
+}
+
+/// run the works of the spec in the topological order, up to `parallel` works at once.
+/// each work runs in `${LW_INDIR}/<work>` and `${LW_OUTDIR}/<work>`.
+async fn run_all(config: &AllConfig) -> Result<()> {
+    let problems = config.spec.validate();
+    if !problems.is_empty() {
+        return KnownErrors::normal(&format!("invalid spec: {}", problems.join("; ")), true);
+    }
+    let mut states: BTreeMap<&str, State> = config
+        .spec
+        .works
+        .keys()
+        .map(|name| (name.as_str(), State::Pending))
+        .collect();
+    let mut running: FuturesUnordered<LocalBoxFuture<(String, Result<RunOutcome>)>> =
+        FuturesUnordered::new();
+    let mut stopped = false;
+    loop {
+        skip_depending_on_failure(&config.spec, &mut states);
+        if !stopped {
+            for name in ready(&config.spec, &states) {
+                // a signal between the works stops scheduling, while the running ones are cancelled by themselves.
+                if cancel::received().is_some() {
+                    stopped = true;
+                }
+                if stopped || config.parallel <= running.len() {
+                    break;
+                }
+                let work_config = match work_config(config, name) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        println!("{}: {}", name, redact(&e.to_string()));
+                        states.insert(name, State::Failed);
+                        stopped = config.on_failure == OnFailure::Stop;
+                        continue;
+                    }
+                };
+                states.insert(name, State::Running);
+                let args = config.spec.works[name].command.clone();
+                running.push(
+                    async move {
+                        let r = run(&args, &work_config).await;
+                        (work_config.work_name.clone(), r)
+                    }
+                    .boxed_local(),
+                );
+            }
+        }
+        let (name, r) = match running.next().await {
+            None => break,
+            Some(x) => x,
+        };
+        let name = states.keys().find(|n| **n == name).copied().unwrap();
+        match r {
+            Ok(RunOutcome::Executed) => {
+                println!("{}: succeeded", name);
+                states.insert(name, State::Succeeded);
+            }
+            Ok(RunOutcome::AlreadySucceeded) => {
+                println!("{}: already succeeded", name);
+                states.insert(name, State::Succeeded);
+            }
+            // the works depending on it are skipped too, which is not a failure.
+            Ok(RunOutcome::Skipped) => {
+                println!("{}: skipped by the program", name);
+                states.insert(name, State::Skipped);
+            }
+            Err(e) => {
+                println!("{}: failed: {}", name, redact(&e.to_string()));
+                states.insert(name, State::Failed);
+                // a signal stops the whole workflow whatever the policy is.
+                let cancelled = matches!(
+                    e.downcast_ref::<KnownErrors>(),
+                    Some(KnownErrors::Cancelled(_))
+                );
+                stopped = stopped || cancelled || config.on_failure == OnFailure::Stop;
+            }
+        }
+    }
+    for (name, state) in states.iter_mut() {
+        if *state == State::Pending {
+            println!("{}: skipped", name);
+            *state = State::Skipped;
+        }
+    }
+
+    let failed = names(&states, State::Failed);
+    let skipped = names(&states, State::Skipped);
+    if let Some(sig) = cancel::received() {
+        return KnownErrors::cancelled(&format!(
+            "{}: cancelled by signal {}, failed [{}], skipped [{}]",
+            config.target_id,
+            sig,
+            failed.join(", "),
+            skipped.join(", ")
+        ));
+    }
+    // without failures, the works are skipped only by the programs.
+    match failed.is_empty() {
+        true => Ok(()),
+        false => KnownErrors::normal(
+            &format!(
+                "{}: failed [{}], skipped [{}]",
+                config.target_id,
+                failed.join(", "),
+                skipped.join(", ")
+            ),
+            true,
+        ),
+    }
+}
+
+fn work_config(config: &AllConfig, name: &str) -> Result<Config> {
+    let work = &config.spec.works[name];
+    if work.command.is_empty() {
+        return KnownErrors::normal(&format!("Work '{}' has no command", name), true);
+    }
+    let mut c = Config::new_from_env_for(
+        config.target_id.clone(),
+        name.to_string(),
+        work.version.clone(),
+        config.spec.depends(name)?,
+    )?;
+    c.indir = format!("{}/{}", c.indir, name);
+    c.outdir = format!("{}/{}", c.outdir, name);
+    for dir in [&c.indir, &c.outdir].iter() {
+        std::fs::create_dir_all(dir).known_error(&format!("fail to mkdir: {}", dir), false)?;
+    }
+    c.force = c.force || config.force;
+    Ok(c)
+}
+
+/// the pending works whose depends are all succeeded, in the order of the names.
+fn ready<'a>(spec: &'a Spec, states: &BTreeMap<&'a str, State>) -> Vec<&'a str> {
+    spec.works
+        .iter()
+        .filter(|(name, _)| states.get(name.as_str()) == Some(&State::Pending))
+        .filter(|(_, w)| {
+            w.depends
+                .iter()
+                .all(|d| states.get(d.work.as_str()) == Some(&State::Succeeded))
+        })
+        .map(|(name, _)| name.as_str())
+        .collect()
+}
+
+/// mark the pending works which never become ready as skipped, transitively.
+fn skip_depending_on_failure<'a>(spec: &'a Spec, states: &mut BTreeMap<&'a str, State>) {
+    loop {
+        let skipped = spec
+            .works
+            .iter()
+            .filter(|(name, _)| states.get(name.as_str()) == Some(&State::Pending))
+            .filter(|(_, w)| {
+                w.depends.iter().any(|d| {
+                    matches!(
+                        states.get(d.work.as_str()),
+                        Some(State::Failed) | Some(State::Skipped)
+                    )
+                })
+            })
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        if skipped.is_empty() {
+            return;
+        }
+        for name in skipped.into_iter() {
+            println!("{}: skipped", name);
+            states.insert(name, State::Skipped);
+        }
+    }
+}
+
+fn names(states: &BTreeMap<&str, State>, state: State) -> Vec<String> {
+    states
+        .iter()
+        .filter(|(_, s)| **s == state)
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::record::WorkStatus;
+    use serial_test::serial;
+
+    async fn setup(spec: &str, parallel: usize, on_failure: OnFailure) -> Result<AllConfig> {
+        crate::envvar::set_test_defaults();
+        cancel::install()?;
+        cancel::reset();
+        let config = Config::new_from_env()?;
+        for dir in [&config.indir, &config.outdir].iter() {
+            let _ = std::fs::remove_dir_all(dir);
+        }
+        crate::artifact::reset(&config.artifact_connector).await?;
+        let mut mc = config.record_connector.connect().await?;
+        mc.delete_all().await?;
+        Ok(AllConfig {
+            spec: Spec::parse(spec)?,
+            target_id: config.target_id,
+            parallel,
+            on_failure,
+            force: false,
+        })
+    }
+
+    async fn work_statuses(config: &AllConfig) -> Result<BTreeMap<String, WorkStatus>> {
+        let c = Config::new_from_env()?;
+        let mut mc = c.record_connector.connect().await?;
+        let record = mc.get_or_default(&config.target_id).await?;
+        Ok(record
+            .works
+            .into_iter()
+            .map(|(name, w)| (name, w.status))
+            .collect())
+    }
+
+    const SPEC: &str = r#"
+works:
+  a:
+    version: "1"
+    command: [/bin/bash, -c, "echo -n a > $LW_OUTDIR/artifacts/a.txt"]
+    produces: [a.txt]
+  b:
+    version: "1"
+    command: [/bin/bash, -c, "cp $LW_INDIR/artifacts/a/a.txt $LW_OUTDIR/artifacts/b.txt"]
+    depends: [{work: a, artifacts: [a.txt]}]
+    produces: [b.txt]
+  c:
+    version: "1"
+    command: [/bin/bash, -c, "test -f $LW_INDIR/artifacts/a/a.txt"]
+    depends: [{work: a, artifacts: [a.txt]}]
+  d:
+    version: "1"
+    command: [/bin/true]
+    depends: [{work: b}, {work: c}]
+"#;
+
+    #[async_std::test]
+    #[serial]
+    async fn test_run_all() -> Result<()> {
+        let config = setup(SPEC, 2, OnFailure::Stop).await?;
+        run_all(&config).await?;
+        let statuses = work_statuses(&config).await?;
+        assert_eq!(statuses.len(), 4);
+        assert!(statuses.values().all(|s| *s == WorkStatus::Succeeded));
+
+        // all of them are already succeeded
+        run_all(&config).await?;
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_run_all_on_failure() -> Result<()> {
+        let spec = r#"
+works:
+  a:
+    version: "1"
+    command: [/bin/false]
+  b:
+    version: "1"
+    command: [/bin/true]
+    depends: [{work: a}]
+  c:
+    version: "1"
+    command: [/bin/true]
+"#;
+        let config = setup(spec, 1, OnFailure::Stop).await?;
+        assert!(run_all(&config).await.is_err());
+        let statuses = work_statuses(&config).await?;
+        assert_eq!(statuses.get("a"), Some(&WorkStatus::FailPermanent));
+        assert_eq!(statuses.get("b"), None);
+        assert_eq!(statuses.get("c"), None);
+
+        let config = setup(spec, 1, OnFailure::Continue).await?;
+        assert!(run_all(&config).await.is_err());
+        let statuses = work_statuses(&config).await?;
+        assert_eq!(statuses.get("a"), Some(&WorkStatus::FailPermanent));
+        assert_eq!(statuses.get("b"), None);
+        assert_eq!(statuses.get("c"), Some(&WorkStatus::Succeeded));
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_run_all_skipped() -> Result<()> {
+        let spec = r#"
+works:
+  a:
+    version: "1"
+    command: [/bin/bash, -c, "exit 99"]
+  b:
+    version: "1"
+    command: [/bin/true]
+    depends: [{work: a}]
+  c:
+    version: "1"
+    command: [/bin/true]
+"#;
+        let config = setup(spec, 1, OnFailure::Stop).await?;
+        std::env::set_var("LW_EXIT_CODES", "99=skipped");
+        let r = run_all(&config).await;
+        std::env::remove_var("LW_EXIT_CODES");
+        assert_matches::assert_matches!(r, Ok(()));
+        let statuses = work_statuses(&config).await?;
+        assert_eq!(statuses.get("a"), Some(&WorkStatus::Skipped));
+        assert_eq!(statuses.get("b"), None);
+        assert_eq!(statuses.get("c"), Some(&WorkStatus::Succeeded));
+        Ok(())
+    }
+
+    #[async_std::test]
+    #[serial]
+    async fn test_run_all_cancelled() -> Result<()> {
+        let config = setup(SPEC, 1, OnFailure::Continue).await?;
+        // the signal which arrives between the works
+        unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+        while cancel::received().is_none() {
+            async_std::task::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        let r = run_all(&config).await;
+        cancel::reset();
+        assert_eq!(
+            r.unwrap_err().downcast_ref::<KnownErrors>(),
+            Some(&KnownErrors::Cancelled(format!(
+                "{}: cancelled by signal {}, failed [], skipped [a, b, c, d]",
+                config.target_id,
+                libc::SIGTERM
+            )))
+        );
+        assert!(work_statuses(&config).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,38 @@
+use crate::error::{KnownErrorsHelper, Result};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+const SIGNALS: [libc::c_int; 2] = [libc::SIGTERM, libc::SIGINT];
+
+fn flag() -> &'static Arc<AtomicUsize> {
+    static FLAG: OnceLock<Arc<AtomicUsize>> = OnceLock::new();
+    FLAG.get_or_init(|| Arc::new(AtomicUsize::new(0)))
+}
+
+/// install the handlers of SIGTERM and SIGINT, which only remember the signal instead of terminating the executor,
+/// so that it can forward the signal to the program and record the cancellation.
+/// it is called once per process before the works run, since the signal must not be forgotten between the works.
+pub fn install() -> Result<()> {
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    if !INSTALLED.swap(true, Ordering::SeqCst) {
+        for sig in SIGNALS.iter() {
+            signal_hook::flag::register_usize(*sig, flag().clone(), *sig as usize)
+                .known_error(&format!("fail to register signal {}", sig), true)?;
+        }
+    }
+    Ok(())
+}
+
+/// forget the signal which the previous test sent.
+#[cfg(test)]
+pub fn reset() {
+    flag().store(0, Ordering::SeqCst);
+}
+
+/// returns the last signal received after `install`.
+pub fn received() -> Option<libc::c_int> {
+    match flag().load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig as libc::c_int),
+    }
+}
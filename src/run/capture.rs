@@ -0,0 +1,137 @@
+use crate::error::{KnownErrorsHelper, Result};
+use crate::redact::redact;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// The output of the child process is teed to the console and `<dir>/{stdout,stderr}.log`.
+/// Each log file is truncated at `max_bytes`, and the last `tail_lines` lines of stderr are kept.
+/// A line longer than `max_bytes` is split, so that the program can not exhaust the memory without newlines.
+/// The credentials in the output are redacted.
+pub struct Capture {
+    stdout: Option<JoinHandle<VecDeque<String>>>,
+    stderr: Option<JoinHandle<VecDeque<String>>>,
+}
+
+impl Capture {
+    pub fn spawn(
+        child: &mut std::process::Child,
+        dir: &Path,
+        max_bytes: u64,
+        tail_lines: usize,
+    ) -> Result<Self> {
+        std::fs::create_dir_all(dir)
+            .known_error(&format!("fail to mkdir: {}", dir.display()), false)?;
+        let stdout = match child.stdout.take() {
+            None => None,
+            Some(r) => {
+                let file = create(&dir.join("stdout.log"))?;
+                Some(std::thread::spawn(move || {
+                    pump(r, std::io::stdout(), file, max_bytes, 0)
+                }))
+            }
+        };
+        let stderr = match child.stderr.take() {
+            None => None,
+            Some(r) => {
+                let file = create(&dir.join("stderr.log"))?;
+                Some(std::thread::spawn(move || {
+                    pump(r, std::io::stderr(), file, max_bytes, tail_lines)
+                }))
+            }
+        };
+        Ok(Self { stdout, stderr })
+    }
+
+    /// wait until the output is closed, and returns the tail of stderr.
+    /// the threads are joined off the executor, and at most `wait`, since a daemonized grandchild may keep it open forever.
+    pub async fn join(self, wait: Duration) -> String {
+        let joined = async_std::task::spawn_blocking(move || {
+            if let Some(h) = self.stdout {
+                let _ = h.join();
+            }
+            match self.stderr.map(|h| h.join()) {
+                Some(Ok(tail)) => tail.into_iter().collect::<Vec<_>>().join("\n"),
+                _ => String::new(),
+            }
+        });
+        match async_std::future::timeout(wait, joined).await {
+            Ok(tail) => tail,
+            Err(_) => {
+                println!("the output is still open after the program exits, stop capturing it");
+                String::new()
+            }
+        }
+    }
+}
+
+fn create(path: &Path) -> Result<std::fs::File> {
+    let file = std::fs::File::create(path)
+        .known_error(&format!("fail to create file: {}", path.display()), false)?;
+    Ok(file)
+}
+
+fn pump(
+    r: impl Read,
+    mut console: impl Write,
+    mut file: std::fs::File,
+    max_bytes: u64,
+    tail_lines: usize,
+) -> VecDeque<String> {
+    let mut reader = BufReader::new(r);
+    let mut tail = VecDeque::new();
+    let mut written = 0u64;
+    let mut truncated = false;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match (&mut reader)
+            .take(max_bytes.max(1))
+            .read_until(b'\n', &mut line)
+        {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+        let line = redact(&String::from_utf8_lossy(&line)).into_bytes();
+        let _ = console.write_all(&line);
+        let _ = console.flush();
+        if !truncated {
+            if written + line.len() as u64 <= max_bytes {
+                let _ = file.write_all(&line);
+                written += line.len() as u64;
+            } else {
+                let _ = file.write_all(b"[truncated]\n");
+                truncated = true;
+            }
+        }
+        if 0 < tail_lines {
+            if tail.len() == tail_lines {
+                tail.pop_front();
+            }
+            let s = String::from_utf8_lossy(&line);
+            tail.push_back(s.trim_end_matches(['\r', '\n']).to_string());
+        }
+    }
+    let _ = file.sync_all();
+    tail
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pump_long_line() {
+        let path =
+            std::env::temp_dir().join(format!("loadwork-test-pump-{}.log", std::process::id()));
+        let file = create(&path).unwrap();
+        let mut console = Vec::new();
+        let tail = pump(&b"0123456789\nab\n"[..], &mut console, file, 4, 3);
+        assert_eq!(console, b"0123456789\nab\n");
+        assert_eq!(tail, vec!["4567", "89", "ab"]);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "0123[truncated]\n");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,157 @@
+use crate::artifact::Publish;
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use crate::record::{check_fields, Document};
+use async_std::path::Path;
+
+/// the status which the program gives in `result.json`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResultStatus {
+    Succeeded,
+    Skipped,
+    Retryable,
+    Permanent,
+}
+
+/// `$LW_OUTDIR/result.json`, which the program writes optionally.
+/// the unknown keys are ignored with a warning, since the programs may have written their own result.json.
+///
+/// ```json
+/// {
+///   "status": "succeeded",
+///   "message": "3 speakers found",
+///   "artifacts": ["text.json", { "name": "*.vtt", "content_type": "text/vtt" }],
+///   "fields": { "language": "ja" }
+/// }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, serde::Deserialize)]
+pub struct ChildResult {
+    /// overrides the status by the exit code.
+    #[serde(default)]
+    pub status: Option<ResultStatus>,
+    #[serde(default)]
+    pub message: Option<String>,
+    /// the artifacts to upload. all files under `$LW_OUTDIR/artifacts` if it is omitted.
+    #[serde(default)]
+    pub artifacts: Option<Vec<Publish>>,
+    /// the top-level fields to set to the workflow record.
+    #[serde(default)]
+    pub fields: Document,
+}
+
+const KEYS: &[&str] = &["status", "message", "artifacts", "fields"];
+
+impl ChildResult {
+    pub fn parse(s: &str) -> Result<Self> {
+        let value: serde_json::Value =
+            serde_json::from_str(s).known_error("malformed result.json", true)?;
+        let obj = match value.as_object() {
+            Some(obj) => obj,
+            None => return KnownErrors::normal("malformed result.json: not an object", true),
+        };
+        let unknown: Vec<&str> = obj
+            .keys()
+            .map(|k| k.as_str())
+            .filter(|k| !KEYS.contains(k))
+            .collect();
+        if !unknown.is_empty() {
+            println!(
+                "result.json: unknown keys are ignored: {}",
+                unknown.join(", ")
+            );
+        }
+        let r: Self = serde_json::from_value(value).known_error("malformed result.json", true)?;
+        check_fields(&r.fields).known_error("invalid result.json", true)?;
+        Ok(r)
+    }
+
+    /// `None` if the program did not write it.
+    pub async fn read_opt(path: &Path) -> Result<Option<Self>> {
+        if !path.exists().await {
+            return Ok(None);
+        }
+        let s = async_std::fs::read_to_string(path)
+            .await
+            .known_error(&format!("fail to read: {}", path.display()), true)?;
+        Ok(Some(Self::parse(&s)?))
+    }
+
+    /// the result of the status, which is `None` if it is not given and the exit code decides.
+    pub fn check(&self, pg: &str) -> Option<Result<()>> {
+        let status = self.status?;
+        let msg = match self.message {
+            Some(ref m) => format!("{}: {}", pg, m),
+            None => format!(
+                "{}: {} by result.json",
+                pg,
+                format!("{:?}", status).to_lowercase()
+            ),
+        };
+        match status {
+            ResultStatus::Succeeded => Some(Ok(())),
+            ResultStatus::Skipped => Some(KnownErrors::skipped(&msg)),
+            ResultStatus::Retryable => Some(KnownErrors::normal(&msg, false)),
+            ResultStatus::Permanent => Some(KnownErrors::normal(&msg, true)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_result() {
+        let r = ChildResult::parse(
+            r#"{"status": "skipped", "message": "no voice",
+                "artifacts": ["a.txt", {"name": "*.vtt", "content_type": "text/vtt"}],
+                "fields": {"language": "ja"}}"#,
+        )
+        .unwrap();
+        assert_eq!(r.status, Some(ResultStatus::Skipped));
+        assert_eq!(
+            r.artifacts.as_ref().unwrap()[1],
+            Publish {
+                name: "*.vtt".to_string(),
+                content_type: Some("text/vtt".to_string())
+            }
+        );
+        assert_eq!(r.fields.get_str("language"), Ok("ja"));
+        assert_eq!(
+            r.check("pg")
+                .unwrap()
+                .unwrap_err()
+                .downcast_ref::<KnownErrors>(),
+            Some(&KnownErrors::Skipped("pg: no voice".to_string()))
+        );
+
+        let r = ChildResult::parse("{}").unwrap();
+        assert_eq!(r, ChildResult::default());
+        assert!(r.check("pg").is_none());
+        let r = ChildResult::parse(r#"{"status": "succeeded"}"#).unwrap();
+        assert_matches::assert_matches!(r.check("pg"), Some(Ok(())));
+        let r = ChildResult::parse(r#"{"status": "retryable"}"#).unwrap();
+        assert_eq!(
+            r.check("pg")
+                .unwrap()
+                .unwrap_err()
+                .downcast_ref::<KnownErrors>(),
+            Some(&KnownErrors::Normal(
+                "pg: retryable by result.json".to_string(),
+                false
+            ))
+        );
+
+        assert!(ChildResult::parse(r#"{"status": "done"}"#).is_err());
+        // the unknown keys are ignored, e.g. the one of tests/k8s-test.tmpl.yaml
+        assert_eq!(
+            ChildResult::parse(r#"{"rin": "orange"}"#).unwrap(),
+            ChildResult::default()
+        );
+        let r = ChildResult::parse(r#"{"status": "skipped", "stauts": "succeeded"}"#).unwrap();
+        assert_eq!(r.status, Some(ResultStatus::Skipped));
+        assert!(ChildResult::parse("[]").is_err());
+        assert!(ChildResult::parse(r#"{"fields": {"works": 1}}"#).is_err());
+        assert!(ChildResult::parse(r#"{"fields": {"a.b": 1}}"#).is_err());
+    }
+}
@@ -0,0 +1,38 @@
+use crate::error::Result;
+
+#[derive(Debug, Clone)]
+struct Config {
+    work_name: String,
+    depends: Vec<crate::envvar::Depend>,
+    record_connector: crate::record::Connector,
+}
+
+impl Config {
+    pub fn new_from_env() -> Result<Self> {
+        use crate::{envvar, record, spec};
+        let work_name = envvar::work_name()?;
+        let s = Self {
+            depends: spec::depends_from_env(&work_name)?,
+            work_name,
+            record_connector: record::Connector::new_from_env()?,
+        };
+        Ok(s)
+    }
+}
+
+#[allow(dead_code)]
+pub async fn scan_from_env(_args: &[String]) -> Result<()> {
+    let config = Config::new_from_env()?;
+    scan(&config).await
+}
+
+/// print the ids of the targets whose depends are all succeeded and
+/// whose own work is not recorded yet or failed retryably, one per line.
+async fn scan(config: &Config) -> Result<()> {
+    let mc = &mut config.record_connector.connect().await?;
+    let ids = mc.find_ready(&config.work_name, &config.depends).await?;
+    for id in ids.iter() {
+        println!("{}", id);
+    }
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use crate::artifact::{is_pattern, match_artifacts};
+use crate::envvar::Depend;
+use crate::error::{KnownErrors, KnownErrorsHelper, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// the workflow definition, which is written in YAML.
+///
+/// ```yaml
+/// works:
+///   demucs:
+///     version: "3"
+///     command: [python, demucs.py]
+///     produces: [bass.wav, vocal.wav]
+///   whisper:
+///     version: "1"
+///     command: [python, whisper.py]
+///     depends:
+///       - work: demucs
+///         artifacts: [vocal.wav]
+///     produces: [text.json]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    pub works: BTreeMap<String, WorkSpec>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WorkSpec {
+    pub version: String,
+    /// the program and its arguments.
+    #[serde(default)]
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub depends: Vec<DependSpec>,
+    /// the names or glob patterns of the artifacts which the work uploads.
+    #[serde(default)]
+    pub produces: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DependSpec {
+    pub work: String,
+    /// the version of the work which is required. the version in the spec if it is omitted.
+    pub version: Option<String>,
+    /// the names or glob patterns of the artifacts to download, same as `LW_DEPENDS_xxx`.
+    #[serde(default)]
+    pub artifacts: Vec<String>,
+}
+
+impl Spec {
+    pub fn load(path: &str) -> Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .known_error(&format!("fail to read spec: {}", path), true)?;
+        Ok(Self::parse(&s).known_error(&format!("malformed spec: {}", path), true)?)
+    }
+    pub fn parse(s: &str) -> std::result::Result<Self, serde_yaml::Error> {
+        serde_yaml::from_str(s)
+    }
+
+    /// the depends of the work, which `run` checks as `LW_DEPENDS_xxx`.
+    pub fn depends(&self, work_name: &str) -> Result<Vec<Depend>> {
+        let work = match self.works.get(work_name) {
+            None => {
+                return KnownErrors::normal(
+                    &format!("Work '{}' is not defined in the spec", work_name),
+                    true,
+                )
+            }
+            Some(w) => w,
+        };
+        Ok(work
+            .depends
+            .iter()
+            .map(|d| Depend {
+                work_name: d.work.clone(),
+                work_version: d
+                    .version
+                    .clone()
+                    .or_else(|| self.works.get(&d.work).map(|w| w.version.clone()))
+                    .unwrap_or_default(),
+                artifacts: d.artifacts.clone(),
+            })
+            .collect())
+    }
+
+    /// returns the problems of the spec: undefined works, versions which never match,
+    /// artifacts which are not produced, and cycles.
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (name, work) in self.works.iter() {
+            for d in work.depends.iter() {
+                let producer = match self.works.get(&d.work) {
+                    None => {
+                        problems.push(format!(
+                            "Work '{}' depends on '{}' which is not defined",
+                            name, d.work
+                        ));
+                        continue;
+                    }
+                    Some(p) => p,
+                };
+                if let Some(ref version) = d.version {
+                    if *version != producer.version {
+                        problems.push(format!(
+                            "Work '{}' depends on '{}' version mismatched: {} but {}",
+                            name, d.work, version, producer.version
+                        ));
+                    }
+                }
+                for a in d.artifacts.iter() {
+                    match is_produced(a, &producer.produces) {
+                        Ok(true) => (),
+                        Ok(false) => problems.push(format!(
+                            "Work '{}' depends on '{}' which does not produce: {}",
+                            name, d.work, a
+                        )),
+                        Err(e) => problems.push(format!("Work '{}': {}", name, e)),
+                    }
+                }
+            }
+        }
+        problems.extend(
+            self.cycles()
+                .into_iter()
+                .map(|c| format!("cycle: {}", c.join(" -> "))),
+        );
+        problems
+    }
+
+    /// the work names in the topological order, in the order of the names among the independent ones.
+    pub fn order(&self) -> Result<Vec<&str>> {
+        let mut order: Vec<&str> = vec![];
+        while order.len() < self.works.len() {
+            let next = self.works.iter().find(|(name, w)| {
+                !order.contains(&name.as_str())
+                    && w.depends.iter().all(|d| order.contains(&d.work.as_str()))
+            });
+            match next {
+                Some((name, _)) => order.push(name),
+                None => {
+                    return KnownErrors::normal("the works of the spec cannot be ordered", true)
+                }
+            }
+        }
+        Ok(order)
+    }
+
+    /// each cycle is the path of the work names which starts and ends with the same work.
+    fn cycles(&self) -> Vec<Vec<String>> {
+        #[derive(Clone, Copy, PartialEq, Eq)]
+        enum State {
+            Visiting,
+            Done,
+        }
+        fn visit<'a>(
+            spec: &'a Spec,
+            name: &'a str,
+            states: &mut BTreeMap<&'a str, State>,
+            path: &mut Vec<&'a str>,
+            cycles: &mut Vec<Vec<String>>,
+        ) {
+            match states.get(name) {
+                Some(State::Done) => return,
+                Some(State::Visiting) => {
+                    let start = path.iter().position(|n| *n == name).unwrap_or(0);
+                    let mut cycle: Vec<String> =
+                        path[start..].iter().map(|n| n.to_string()).collect();
+                    cycle.push(name.to_string());
+                    cycles.push(cycle);
+                    return;
+                }
+                None => (),
+            }
+            let work = match spec.works.get(name) {
+                None => return,
+                Some(w) => w,
+            };
+            states.insert(name, State::Visiting);
+            path.push(name);
+            for d in work.depends.iter() {
+                visit(spec, &d.work, states, path, cycles);
+            }
+            path.pop();
+            states.insert(name, State::Done);
+        }
+
+        let mut states = BTreeMap::new();
+        let mut cycles = vec![];
+        for name in self.works.keys() {
+            visit(self, name, &mut states, &mut vec![], &mut cycles);
+        }
+        cycles
+    }
+}
+
+/// whether the artifact name or pattern `artifact` can be one of `produces`.
+fn is_produced(artifact: &str, produces: &[String]) -> Result<bool> {
+    if !match_artifacts(artifact, produces)?.is_empty() {
+        return Ok(true);
+    }
+    for p in produces.iter().filter(|p| is_pattern(p)) {
+        // a pattern may match a pattern, which cannot be decided.
+        if is_pattern(artifact) || !match_artifacts(p, &[artifact.to_string()])?.is_empty() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// the depends of the work, from the spec if `LW_SPEC` is given, or from `LW_DEPENDS_xxx`.
+pub fn depends_from_env(work_name: &str) -> Result<Vec<Depend>> {
+    use crate::envvar;
+    match envvar::spec_opt() {
+        None => envvar::depends(),
+        Some(path) => {
+            if !envvar::depends()?.is_empty() {
+                return KnownErrors::normal("LW_DEPENDS_xxx cannot be used with LW_SPEC", true);
+            }
+            Spec::load(&path)?.depends(work_name)
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub async fn validate_from_env(args: &[String]) -> Result<()> {
+    let path = match args {
+        [path] => path,
+        _ => return KnownErrors::normal("usage: validate <spec>", true),
+    };
+    let problems = Spec::load(path)?.validate();
+    for p in problems.iter() {
+        println!("{}", p);
+    }
+    match problems.len() {
+        0 => Ok(()),
+        n => KnownErrors::normal(&format!("{}: {} problems found", path, n), true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+works:
+  demucs:
+    version: "3"
+    command: [python, demucs.py]
+    produces: [bass.wav, vocal.wav, "stems/*.wav"]
+  whisper:
+    version: "1"
+    depends:
+      - work: demucs
+        artifacts: [vocal.wav, stems/drums.wav]
+"#;
+
+    #[test]
+    fn test_spec_depends() {
+        let spec = Spec::parse(SPEC).unwrap();
+        assert_eq!(spec.works["demucs"].command, vec!["python", "demucs.py"]);
+        assert_eq!(spec.validate(), Vec::<String>::new());
+        assert_eq!(spec.depends("demucs").unwrap(), vec![]);
+        assert_eq!(
+            spec.depends("whisper").unwrap(),
+            vec![Depend {
+                work_name: "demucs".to_string(),
+                work_version: "3".to_string(),
+                artifacts: vec!["vocal.wav".to_string(), "stems/drums.wav".to_string()],
+            }]
+        );
+        assert!(spec.depends("unknown").is_err());
+        assert_eq!(spec.order().unwrap(), vec!["demucs", "whisper"]);
+    }
+
+    #[test]
+    fn test_spec_validate() {
+        let spec = Spec::parse(
+            r#"
+works:
+  a:
+    version: "1"
+    depends:
+      - work: c
+  b:
+    version: "1"
+    depends:
+      - work: a
+        version: "2"
+        artifacts: [x.txt]
+      - work: missing
+  c:
+    version: "1"
+    depends:
+      - work: b
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            spec.validate(),
+            vec![
+                "Work 'b' depends on 'a' version mismatched: 2 but 1",
+                "Work 'b' depends on 'a' which does not produce: x.txt",
+                "Work 'b' depends on 'missing' which is not defined",
+                "cycle: a -> c -> b -> a",
+            ]
+        );
+        assert!(spec.order().is_err());
+        assert!(Spec::parse("works:\n  a:\n    versoin: \"1\"\n").is_err());
+    }
+}
@@ -0,0 +1,37 @@
+use std::process::{Command, Output};
+
+fn run_true(target_id: &str, dir: &std::path::Path) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_loadwork"))
+        .args(["run", "true"])
+        .env_clear()
+        .env("PATH", std::env::var("PATH").unwrap_or_default())
+        .env("LW_TARGET_ID", target_id)
+        .env("LW_WORK_NAME", "foobar")
+        .env("LW_WORK_VERSION", "1")
+        .env("LW_INDIR", dir.join("in"))
+        .env("LW_OUTDIR", dir.join("out"))
+        .env(
+            "LW_RECORD_BACKEND",
+            format!("file://{}", dir.join("records").display()),
+        )
+        .env(
+            "LW_ARTIFACT_BACKEND",
+            format!("file://{}", dir.join("artifacts").display()),
+        )
+        .env("LW_ALREADY_SUCCEEDED_EXIT_CODE", "3")
+        .output()
+        .unwrap()
+}
+
+/// the second run of a succeeded work exits with LW_ALREADY_SUCCEEDED_EXIT_CODE.
+#[test]
+fn test_bin_already_succeeded_exit_code() {
+    let dir = std::env::temp_dir().join(format!("lw-test-bin-exit-code-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let target_id = "test-bin-exit-code";
+    let output = run_true(target_id, &dir);
+    assert_eq!(output.status.code(), Some(0), "{:?}", output);
+    let output = run_true(target_id, &dir);
+    assert_eq!(output.status.code(), Some(3), "{:?}", output);
+    let _ = std::fs::remove_dir_all(&dir);
+}
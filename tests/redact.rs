@@ -0,0 +1,24 @@
+use std::process::Command;
+
+/// no credential reaches stdout or stderr when the connection string is invalid.
+#[test]
+fn test_bin_redact() {
+    let password = "lw-test-bin-password";
+    let output = Command::new(env!("CARGO_BIN_EXE_loadwork"))
+        .args(["history", "t1"])
+        .env_clear()
+        .env(
+            "LW_MONGODB_URI",
+            format!("mongodb://lwuser:{}@localhost:99999/db", password),
+        )
+        .env("LW_MONGODB_COLLECTION", "c")
+        .env("LW_RETRY_RECORD_MAX_ATTEMPTS", "1")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(stdout.contains("fail to connect"), "{}", stdout);
+    assert!(!stdout.contains(password), "{}", stdout);
+    assert!(!stderr.contains(password), "{}", stderr);
+}